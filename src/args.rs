@@ -4,12 +4,41 @@ use clap::{Parser, Subcommand, ValueEnum};
 pub enum RenderType {
     /// Render messages in Markdown format (GitHub Flavored Markdown)
     Markdown,
+    /// Render messages as a self-contained HTML page
+    Html,
+    /// Render branching structure as Graphviz DOT
+    Dot,
+    /// Render branching structure as Mermaid flowchart
+    Mermaid,
 }
 
 impl ToString for RenderType {
     fn to_string(&self) -> String {
         match self {
             RenderType::Markdown => "markdown".to_string(),
+            RenderType::Html => "html".to_string(),
+            RenderType::Dot => "dot".to_string(),
+            RenderType::Mermaid => "mermaid".to_string(),
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Debug)]
+pub enum UnknownTags {
+    /// Abort on unknown tags
+    Error,
+    /// Keep unknown tags as placeholders like [tag:name k=v] and warn
+    Placeholder,
+    /// Drop unknown tags and warn
+    Ignore,
+}
+
+impl std::fmt::Display for UnknownTags {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UnknownTags::Error => write!(f, "error"),
+            UnknownTags::Placeholder => write!(f, "placeholder"),
+            UnknownTags::Ignore => write!(f, "ignore"),
         }
     }
 }
@@ -22,14 +51,24 @@ pub enum MessageCmds {
         file: String,
     },
     Render {
-        /// AST file want to render
+        /// AST file want to render. For html, it can also be a directory,
+        /// every .ast file is rendered to a page with an index page
         file: String,
         #[arg(short, long)]
-        /// Output file, by default, it print to stdout
+        /// Output file, by default, it print to stdout.
+        /// Output directory if a directory is rendered
         output: Option<String>,
         /// Output format
         #[arg(short, long, default_value_t = RenderType::Markdown)]
         r#type: RenderType,
+        #[arg(long)]
+        /// Show voice files of dialogues
+        voice: bool,
+        #[arg(short, long, value_delimiter = ',')]
+        /// Languages to render in markdown, like `ja` or `ja,cn`.
+        /// If more than one language is given, a table with a column for each language is rendered.
+        /// If not specified, it will use the first language found
+        lang: Vec<String>,
     },
     /// Convert it to GalTransl JSON format
     ToJson {
@@ -42,8 +81,120 @@ pub enum MessageCmds {
         output: String,
         #[arg(short, long)]
         /// Specify the language of the messages
-        /// If not specified, it will use the first language found
+        /// If not specified, it will use the first language found, languages are
+        /// sorted by key so `cn` added by from-json is found before `ja`
         lang: Option<String>,
+        #[arg(long)]
+        /// Include voices of dialogues in voice field
+        voice: bool,
+        #[arg(long)]
+        /// Include lines of messages in AST file in line field
+        line: bool,
+    },
+    /// Write translated GalTransl JSON back into AST files
+    FromJson {
+        /// AST file to write into or directory to search for .ast files
+        /// If empty, use current working directory
+        file: Vec<String>,
+        #[arg(short, long)]
+        /// Translated JSON file if file is a single file otherwise it will be
+        /// the directory which contains JSON files
+        json: String,
+        #[arg(short, long)]
+        /// Output file if file is a single file otherwise it will be
+        /// output directory. If not specified, AST files are modified in place
+        output: Option<String>,
+        #[arg(short, long)]
+        /// Specify the language of the exported messages
+        /// If not specified, it will use the first language found, use the same
+        /// language as to-json if --target-lang sorts before it
+        lang: Option<String>,
+        #[arg(short, long)]
+        /// Language key to write translations into (e.g. cn)
+        /// If not specified, the source language will be replaced
+        target_lang: Option<String>,
+        #[arg(short, long)]
+        /// JSON exported by to-json before translating (file or directory like --json)
+        /// If specified, changed source messages are reported as errors
+        source: Option<String>,
+    },
+    /// Add a new language to text blocks by cloning an existing language,
+    /// use from-json with --target-lang to fill it later
+    AddLang {
+        /// AST file to process or directory to search for .ast files
+        /// If empty, use current working directory
+        file: Vec<String>,
+        #[arg(short, long)]
+        /// Source language to clone (e.g. ja)
+        lang: String,
+        #[arg(short, long)]
+        /// New language to add (e.g. cn)
+        target_lang: String,
+        #[arg(short, long)]
+        /// Output file if file is a single file otherwise it will be
+        /// output directory. If not specified, AST files are modified in place
+        output: Option<String>,
+        #[arg(long)]
+        /// Overwrite the new language if it already exists
+        overwrite: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum GraphCmds {
+    /// Build scenario graph from files and print it in debug format
+    Test {
+        /// AST files or directories to search for .ast files
+        /// If empty, use current working directory
+        files: Vec<String>,
+    },
+    /// List blocks reachable from the top of files
+    Reachable {
+        /// AST files or directories to search for .ast files
+        /// If empty, use current working directory
+        files: Vec<String>,
+        #[arg(short, long)]
+        /// Start block (FILE:BLOCK), by default, start from the top of every file
+        from: Vec<String>,
+    },
+    /// Print the shortest path from the top of file and the predecessors of a block
+    Path {
+        /// AST files or directories to search for .ast files
+        /// If empty, use current working directory
+        files: Vec<String>,
+        #[arg(short, long)]
+        /// Block to find (FILE:BLOCK)
+        to: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum AstCmds {
+    /// Print changes between two AST files by path of values,
+    /// blocks are paired by name so reordered blocks are not changes
+    Diff {
+        /// Original AST file
+        old: String,
+        /// Changed AST file
+        new: String,
+        #[arg(short, long)]
+        /// Only report changes of texts in blocks
+        text_only: bool,
+    },
+    /// Three-way merge of AST files by block, like a patched file of the game
+    /// with the translated old file. Languages in texts are merged one by one,
+    /// conflicts keep ours and are marked with comments
+    Merge {
+        /// Common ancestor, like the original old file
+        base: String,
+        /// Our changes, like the translated old file
+        ours: String,
+        /// Their changes, like the original new file
+        theirs: String,
+        #[arg(short, long)]
+        /// Output file, `-` for stdout. If not specified, ours is modified in place,
+        /// but it is not written if there are conflicts
+        output: Option<String>,
     },
 }
 
@@ -62,12 +213,38 @@ pub enum Commands {
         #[arg(short, long)]
         /// Sort blocks in AST file
         sort_blocks: bool,
+        #[arg(long)]
+        /// Do not write files, print the diff of files which are not formatted
+        /// and exit with error if any
+        check: bool,
     },
     /// Process messages from Artemis Engine
     Message {
         #[command(subcommand)]
         cmd: MessageCmds,
     },
+    /// Analyze branches between blocks and files
+    Graph {
+        #[command(subcommand)]
+        cmd: GraphCmds,
+    },
+    /// Compare and merge AST files
+    Ast {
+        #[command(subcommand)]
+        cmd: AstCmds,
+    },
+    /// Check that files are unchanged after dumping and parsing them again
+    Verify {
+        /// AST files or directories to search for .ast files
+        /// If empty, use current working directory
+        files: Vec<String>,
+    },
+    /// Report unreachable blocks, duplicate blocks and broken links, labels, selects and excalls
+    Lint {
+        /// AST files or directories to search for .ast files
+        /// If empty, use current working directory
+        files: Vec<String>,
+    },
 }
 
 /// Tools to process Artemis Engine AST files
@@ -89,6 +266,35 @@ pub struct Arg {
     #[arg(global = true, short, long)]
     /// Print backtrace on error
     pub backtrace: bool,
+    #[arg(global = true, long, default_value_t = UnknownTags::Placeholder)]
+    /// How to handle unknown inline tags in dialogues
+    pub unknown_tags: UnknownTags,
+    #[arg(global = true, long = "tag", value_name = "NAME=RENDER")]
+    /// Register an inline tag and how it is rendered in messages.
+    /// RENDER can be ignore, placeholder or text:<TEXT>
+    pub tags: Vec<String>,
+    #[arg(global = true, long)]
+    /// Also extract messages from blocks reachable through select and excall
+    /// in the same file, not only the linknext chain from top
+    pub follow_branches: bool,
+    #[arg(global = true, long)]
+    /// Keep Lua comments in AST files when they are written back
+    pub keep_comments: bool,
+    #[arg(global = true, long, value_parser = crate::encoding::parse_encoding)]
+    /// Encoding of AST files, like utf-8, shift_jis or utf-16le.
+    /// If not specified, it is detected from BOM and content
+    pub encoding: Option<&'static encoding_rs::Encoding>,
+    #[arg(global = true, long)]
+    /// Write AST files in their original encoding instead of UTF-8
+    pub keep_encoding: bool,
+    #[arg(global = true, long)]
+    /// Write AST files with LF line endings, a final newline and without BOM
+    /// instead of keeping them from the original files
+    pub normalize: bool,
+    #[arg(global = true, long, value_name = "SUFFIX")]
+    /// Keep the previous version of AST files which are overwritten,
+    /// in the file with this suffix like .bak
+    pub backup: Option<String>,
     #[command(subcommand)]
     pub command: Commands,
 }
@@ -0,0 +1,253 @@
+use crate::types::{AstFile, KeyKind, Value};
+use crate::verify::{key_path, member_paths};
+use std::collections::HashSet;
+
+/// A change between two files, addressed by path like `ast.block_00012.text.ja[0][3]`
+#[derive(Clone, Debug)]
+pub enum Change {
+    Added(String, Value),
+    Removed(String, Value),
+    Changed(String, Value, Value),
+}
+
+impl std::fmt::Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Change::Added(path, v) => write!(f, "+ {}: {}", path, value_text(v)),
+            Change::Removed(path, v) => write!(f, "- {}: {}", path, value_text(v)),
+            Change::Changed(path, old, new) => {
+                write!(f, "~ {}: {} -> {}", path, value_text(old), value_text(new))
+            }
+        }
+    }
+}
+
+/// Value in one line, like `{"bg", file="bg01"}`
+pub fn value_text(v: &Value) -> String {
+    match v {
+        Value::Float(f) => f.to_string(),
+        Value::Int(i) => i.to_string(),
+        Value::Str(s) => format!("{:?}", s),
+        Value::KeyVal((k, v, kind)) => {
+            let key = key_path(k, *kind);
+            format!("{}={}", key.trim_start_matches('.'), value_text(v))
+        }
+        Value::Array(arr) => {
+            let members: Vec<String> = arr.iter().map(value_text).collect();
+            format!("{{{}}}", members.join(", "))
+        }
+        Value::Bool(b) => b.to_string(),
+        Value::Nil => "nil".to_string(),
+    }
+}
+
+/// Members of a table with their paths, keyvals are paired with their values
+fn members(arr: &[Value]) -> Vec<(String, &Value)> {
+    member_paths(arr)
+        .into_iter()
+        .zip(arr)
+        .map(|(p, v)| match v {
+            Value::KeyVal((_, v, _)) => (p, v.as_ref()),
+            v => (p, v),
+        })
+        .collect()
+}
+
+/// Compare values, members of tables are paired by key or by the index of
+/// members without key, so reordered keys are not reported as changes.
+pub fn diff_value(old: &Value, new: &Value, path: &str, result: &mut Vec<Change>) {
+    if old == new {
+        return;
+    }
+    match (old, new) {
+        (Value::Array(a), Value::Array(b)) => {
+            let a = members(a);
+            let b = members(b);
+            let mut seen = HashSet::new();
+            for (p, v) in a.iter() {
+                if !seen.insert(p.as_str()) {
+                    continue;
+                }
+                let member = format!("{}{}", path, p);
+                match b.iter().find(|(p2, _)| p2 == p) {
+                    Some((_, v2)) => diff_value(v, v2, &member, result),
+                    None => result.push(Change::Removed(member, (*v).clone())),
+                }
+            }
+            for (p, v) in b.iter() {
+                if seen.insert(p.as_str()) {
+                    result.push(Change::Added(format!("{}{}", path, p), (*v).clone()));
+                }
+            }
+        }
+        _ => result.push(Change::Changed(path.to_string(), old.clone(), new.clone())),
+    }
+}
+
+/// Keys of block members which contain text: `text` and the texts of selects
+fn text_keys(block: &Value) -> HashSet<String> {
+    let mut keys = HashSet::new();
+    keys.insert("text".to_string());
+    for select in block.find_array_attrs("select") {
+        if let Some(text) = select.find_keyval("text").and_then(|v| v.as_str()) {
+            keys.insert(text.to_string());
+        }
+    }
+    keys
+}
+
+/// Only keep the members of block which contain text (see [text_keys]) and the savetitle
+fn text_members(block: &Value, keys: &HashSet<String>) -> Value {
+    match block {
+        Value::Array(arr) => Value::Array(
+            arr.iter()
+                .filter(|v| match v {
+                    Value::KeyVal((k, _, _)) => keys.contains(k),
+                    v => v
+                        .get_member(0)
+                        .and_then(|v| v.as_str())
+                        .is_some_and(|s| s == "savetitle"),
+                })
+                .cloned()
+                .collect(),
+        ),
+        v => v.clone(),
+    }
+}
+
+/// Names of blocks in the order of file, duplicated names are only listed once
+pub fn block_names(f: &AstFile) -> Vec<String> {
+    let mut seen = HashSet::new();
+    match &f.ast {
+        Value::Array(arr) => arr
+            .iter()
+            .filter_map(|v| match v {
+                Value::KeyVal((k, _, _)) if seen.insert(k.clone()) => Some(k.clone()),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Changes from `old` to `new`. Blocks are paired by name, so reordered
+/// blocks are not reported. If `text_only` is set, only changes of texts
+/// in blocks are reported.
+pub fn diff(old: &AstFile, new: &AstFile, text_only: bool) -> Vec<Change> {
+    let mut result = Vec::new();
+    if !text_only {
+        diff_value(
+            &Value::Float(old.astver),
+            &Value::Float(new.astver),
+            "astver",
+            &mut result,
+        );
+        let name = |f: &AstFile| f.astname.clone().map_or(Value::Nil, Value::Str);
+        diff_value(&name(old), &name(new), "astname", &mut result);
+    }
+    let old_blocks = old.get_blocks();
+    let new_blocks = new.get_blocks();
+    let old_names = block_names(old);
+    let new_names = block_names(new);
+    let filter = |block: &Value, other: Option<&Value>| {
+        if !text_only {
+            return block.clone();
+        }
+        let mut keys = text_keys(block);
+        if let Some(other) = other {
+            keys.extend(text_keys(other));
+        }
+        text_members(block, &keys)
+    };
+    let is_empty = |v: &Value| matches!(v, Value::Array(arr) if arr.is_empty());
+    for name in old_names.iter() {
+        let path = format!("ast{}", key_path(name, KeyKind::for_key(name)));
+        let block = &old_blocks[name];
+        match new_blocks.get(name) {
+            Some(new_block) => diff_value(
+                &filter(block, Some(new_block)),
+                &filter(new_block, Some(block)),
+                &path,
+                &mut result,
+            ),
+            None => {
+                let block = filter(block, None);
+                if !text_only || !is_empty(&block) {
+                    result.push(Change::Removed(path, block));
+                }
+            }
+        }
+    }
+    for name in new_names.iter() {
+        if !old_blocks.contains_key(name) {
+            let path = format!("ast{}", key_path(name, KeyKind::for_key(name)));
+            let block = filter(&new_blocks[name], None);
+            if !text_only || !is_empty(&block) {
+                result.push(Change::Added(path, block));
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    const OLD: &str = r#"astver = 2.0
+ast = {
+    block_00000 = {
+        {"bg", file = "bg01"},
+        text = {ja = {{"あ"}}},
+        line = 1,
+    },
+    block_00001 = {
+        text = {ja = {{"い"}}},
+        line = 2,
+    },
+}
+"#;
+
+    fn parse(src: &str) -> AstFile {
+        Parser::new(&src).parse().unwrap()
+    }
+
+    fn changes(old: &str, new: &str, text_only: bool) -> Vec<String> {
+        diff(&parse(old), &parse(new), text_only)
+            .iter()
+            .map(|c| c.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn reordered_blocks_have_no_changes() {
+        let new = r#"astver = 2.0
+ast = {
+    block_00001 = {
+        text = {ja = {{"い"}}},
+        line = 2,
+    },
+    block_00000 = {
+        {"bg", file = "bg01"},
+        text = {ja = {{"あ"}}},
+        line = 1,
+    },
+}
+"#;
+        assert_eq!(changes(OLD, new, false), Vec::<String>::new());
+    }
+
+    #[test]
+    fn text_only_hides_other_changes() {
+        let new = OLD
+            .replace("bg01", "bg02")
+            .replace("line = 2", "line = 3")
+            .replace(r#"{{"い"}}"#, r#"{{"う"}}"#);
+        assert_eq!(
+            changes(OLD, &new, true),
+            vec![r#"~ ast.block_00001.text.ja[0][0]: "い" -> "う""#]
+        );
+        assert_eq!(changes(OLD, &new, false).len(), 3);
+    }
+}
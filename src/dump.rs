@@ -1,40 +1,112 @@
+use crate::parser::parse_number;
 use crate::types::*;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::io::Write;
 
-struct LenChecker {
+/// Escape string for a double quoted Lua string literal
+fn escape_str(s: &str) -> Cow<'_, str> {
+    if !s
+        .chars()
+        .any(|c| c == '"' || c == '\\' || c.is_ascii_control())
+    {
+        return Cow::Borrowed(s);
+    }
+    let mut result = String::with_capacity(s.len() + 2);
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            '\x07' => result.push_str("\\a"),
+            '\x08' => result.push_str("\\b"),
+            '\x0b' => result.push_str("\\v"),
+            '\x0c' => result.push_str("\\f"),
+            c if c.is_ascii_control() => result.push_str(&format!("\\{:03}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    Cow::Owned(result)
+}
+
+/// Default text of number written by dumper
+pub fn format_number(v: &Value) -> String {
+    match v {
+        Value::Float(f) if f.fract() == 0.0 => format!("{:.1}", f),
+        Value::Float(f) => format!("{}", f),
+        Value::Int(i) => format!("{}", i),
+        _ => String::new(),
+    }
+}
+
+/// Text of number, the original spelling is used if it has the same value
+fn number_text(v: &Value, spelling: Option<&String>) -> String {
+    if let Some(s) = spelling
+        && let Some(n) = parse_number(s)
+    {
+        let same = match (&n, v) {
+            (Value::Float(a), Value::Float(b)) => {
+                a.to_bits() == b.to_bits() || (a.is_nan() && b.is_nan())
+            }
+            (Value::Int(a), Value::Int(b)) => a == b,
+            _ => false,
+        };
+        if same {
+            return s.clone();
+        }
+    }
+    format_number(v)
+}
+
+/// Text of key of keyval, like `key`, `["some key"]` or `[1]`
+fn key_text(key: &str, kind: KeyKind) -> Cow<'_, str> {
+    match kind {
+        KeyKind::Name => Cow::Borrowed(key),
+        KeyKind::Str => Cow::Owned(format!("[\"{}\"]", escape_str(key))),
+        KeyKind::Int => Cow::Owned(format!("[{}]", key)),
+    }
+}
+
+struct LenChecker<'a> {
     target_len: usize,
     current_len: usize,
+    numbers: &'a BTreeMap<Vec<usize>, String>,
+    pos: Vec<usize>,
 }
 
-impl LenChecker {
-    fn new(target_len: usize) -> Self {
+impl<'a> LenChecker<'a> {
+    fn new(target_len: usize, numbers: &'a BTreeMap<Vec<usize>, String>, pos: &[usize]) -> Self {
         LenChecker {
             target_len,
             current_len: 0,
+            numbers,
+            pos: pos.to_vec(),
         }
     }
 
     fn check(&mut self, value: &Value) -> bool {
         match value {
-            Value::Float(f) => {
-                if f.fract() == 0.0 {
-                    self.current_len += format!("{:.1}", f).len();
-                } else {
-                    self.current_len += format!("{}", f).len();
-                }
+            Value::Float(_) | Value::Int(_) => {
+                self.current_len += number_text(value, self.numbers.get(&self.pos)).len()
             }
-            Value::Int(i) => self.current_len += format!("{}", i).len(),
-            Value::Str(s) => self.current_len += s.len() + 2,
-            Value::KeyVal((k, v)) => {
-                self.current_len += k.as_bytes().len() + 3;
+            Value::Str(s) => self.current_len += escape_str(s).len() + 2,
+            Value::Bool(b) => self.current_len += if *b { 4 } else { 5 },
+            Value::Nil => self.current_len += 3,
+            Value::KeyVal((k, v, kind)) => {
+                self.current_len += key_text(k, *kind).len() + 3;
                 if !self.check(v) {
                     return false;
                 }
             }
             Value::Array(arr) => {
                 self.current_len += 1;
-                for v in arr {
-                    if !self.check(v) {
+                for (i, v) in arr.iter().enumerate() {
+                    self.pos.push(i);
+                    let ok = self.check(v);
+                    self.pos.pop();
+                    if !ok {
                         return false;
                     }
                     self.current_len += 2;
@@ -49,22 +121,76 @@ impl LenChecker {
     }
 }
 
-pub struct Dumper {
+/// Writer which converts line endings in text to `newline`
+struct NewlineWriter<W: Write> {
+    inner: W,
+    newline: &'static [u8],
+    /// Whether the last byte written is `\r`
+    cr: bool,
+}
+
+impl<W: Write> NewlineWriter<W> {
+    fn new(inner: W, newline: &'static [u8]) -> Self {
+        NewlineWriter {
+            inner,
+            newline,
+            cr: false,
+        }
+    }
+}
+
+impl<W: Write> Write for NewlineWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut out = Vec::with_capacity(buf.len());
+        for c in buf {
+            if self.cr && *c != b'\n' {
+                out.push(b'\r');
+            }
+            self.cr = *c == b'\r';
+            match c {
+                b'\r' => {}
+                b'\n' => out.extend_from_slice(self.newline),
+                c => out.push(*c),
+            }
+        }
+        self.inner.write_all(&out)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.cr {
+            self.inner.write_all(b"\r")?;
+            self.cr = false;
+        }
+        self.inner.flush()
+    }
+}
+
+pub struct Dumper<'a> {
     current_indent: usize,
-    writer: Box<dyn Write>,
+    writer: Box<dyn Write + 'a>,
     indent: Option<usize>,
     max_line_width: usize,
     current_line_width: usize,
+    comments: Comments,
+    numbers: BTreeMap<Vec<usize>, String>,
+    /// Position of the current value, see [Comments::values]
+    pos: Vec<usize>,
+    normalize: bool,
 }
 
-impl Dumper {
-    pub fn new<W: Write + 'static>(writer: W) -> Self {
+impl<'a> Dumper<'a> {
+    pub fn new<W: Write + 'a>(writer: W) -> Self {
         Dumper {
             current_indent: 0,
             writer: Box::new(writer),
             indent: Some(4),
             max_line_width: 100,
             current_line_width: 0,
+            comments: Comments::default(),
+            numbers: BTreeMap::new(),
+            pos: Vec::new(),
+            normalize: false,
         }
     }
 
@@ -80,51 +206,102 @@ impl Dumper {
         self.max_line_width = max_line_width;
     }
 
-    fn dump_f64(&mut self, f: &f64) -> std::io::Result<()> {
-        if f.fract() == 0.0 {
-            write!(self.writer, "{:.1}", f)
-        } else {
-            write!(self.writer, "{}", f)
+    /// Write LF line endings, a final newline and no BOM instead of keeping them from file
+    pub fn set_normalize(&mut self, normalize: bool) {
+        self.normalize = normalize;
+    }
+
+    fn dump_number(&mut self, v: &Value) -> std::io::Result<()> {
+        let text = number_text(v, self.numbers.get(&self.pos));
+        self.writer.write_all(text.as_bytes())
+    }
+
+    /// `astver` is always a float, but an integer spelling like `2` is kept
+    fn dump_astver(&mut self, astver: f64) -> std::io::Result<()> {
+        if let Some(s) = self.numbers.get(&self.pos)
+            && let Some(Value::Int(i)) = parse_number(s)
+            && i as f64 == astver
+        {
+            return self.writer.write_all(s.as_bytes());
         }
+        self.dump_number(&Value::Float(astver))
+    }
+
+    fn dump_str(&mut self, s: &str) -> std::io::Result<()> {
+        self.writer.write_all(b"\"")?;
+        self.writer.write_all(escape_str(s).as_bytes())?;
+        self.writer.write_all(b"\"")
+    }
+
+    /// Write comments before the value at the position, each on its own line
+    fn dump_comments(&mut self, pos: &[usize]) -> std::io::Result<()> {
+        let comments = match self.comments.get(pos) {
+            Some(comments) => comments.clone(),
+            None => return Ok(()),
+        };
+        for c in comments {
+            if self.indent.is_some() {
+                self.dump_indent()?;
+            }
+            self.writer.write_all(c.as_bytes())?;
+            self.writer.write_all(b"\n")?;
+        }
+        Ok(())
     }
 
     pub fn dump(mut self, ast: &AstFile) -> std::io::Result<()> {
+        self.comments = ast.comments.clone();
+        self.numbers = ast.numbers.clone();
+        let newline = if self.normalize {
+            LineEnding::Lf
+        } else {
+            ast.line_ending
+        };
+        let writer = std::mem::replace(&mut self.writer, Box::new(std::io::sink()));
+        self.writer = Box::new(NewlineWriter::new(writer, newline.as_bytes()));
+        if ast.bom && !self.normalize {
+            self.writer.write_all("\u{FEFF}".as_bytes())?;
+        }
+        self.dump_comments(&[])?;
         if self.indent.is_none() {
-            self.writer.write(b"astver=")?;
-            self.dump_f64(&ast.astver)?;
+            self.writer.write_all(b"astver=")?;
+            self.dump_astver(ast.astver)?;
             if let Some(astname) = &ast.astname {
-                self.writer.write(b"\nastname = \"")?;
-                self.writer.write(astname.as_bytes())?;
+                self.writer.write_all(b"\nastname=")?;
+                self.dump_str(astname)?;
             };
-            self.writer.write(b"\"\nast=")?;
+            self.writer.write_all(b"\nast=")?;
             self.dump_value(&ast.ast)?;
         } else {
-            self.writer.write(b"astver = ")?;
-            self.dump_f64(&ast.astver)?;
+            self.writer.write_all(b"astver = ")?;
+            self.dump_astver(ast.astver)?;
             if let Some(astname) = &ast.astname {
-                self.writer.write(b"\nastname = \"")?;
-                self.writer.write(astname.as_bytes())?;
+                self.writer.write_all(b"\nastname = ")?;
+                self.dump_str(astname)?;
             };
-            self.writer.write(b"\"\nast = ")?;
+            self.writer.write_all(b"\nast = ")?;
             self.current_line_width = 6;
             self.dump_value(&ast.ast)?;
         }
-        self.writer.write(b"\n")?;
-        Ok(())
+        for c in ast.comments.trailing.iter() {
+            self.writer.write_all(b"\n")?;
+            self.writer.write_all(c.as_bytes())?;
+        }
+        if ast.final_newline || self.normalize {
+            self.writer.write_all(b"\n")?;
+        }
+        self.writer.flush()
     }
 
     fn dump_value(&mut self, v: &Value) -> std::io::Result<()> {
         if self.indent.is_none() {
             match v {
-                Value::Float(f) => self.dump_f64(f)?,
-                Value::Int(i) => write!(self.writer, "{}", i)?,
-                Value::Str(s) => {
-                    self.writer.write(b"\"")?;
-                    self.writer.write(s.as_bytes())?;
-                    self.writer.write(b"\"")?;
-                }
-                Value::KeyVal((k, v)) => {
-                    self.writer.write(k.as_bytes())?;
+                Value::Float(_) | Value::Int(_) => self.dump_number(v)?,
+                Value::Str(s) => self.dump_str(s)?,
+                Value::Bool(b) => write!(self.writer, "{}", b)?,
+                Value::Nil => self.writer.write_all(b"nil")?,
+                Value::KeyVal((k, v, kind)) => {
+                    self.writer.write_all(key_text(k, *kind).as_bytes())?;
                     self.writer.write(b"=")?;
                     self.dump_value(v)?;
                 }
@@ -134,29 +311,37 @@ impl Dumper {
                         if i > 0 {
                             self.writer.write(b",")?;
                         }
+                        self.pos.push(i);
+                        self.dump_comments(&self.pos.clone())?;
                         self.dump_value(v)?;
+                        self.pos.pop();
                     }
+                    self.pos.push(arr.len());
+                    self.dump_comments(&self.pos.clone())?;
+                    self.pos.pop();
                     self.writer.write(b"}")?;
                 }
             }
         } else {
             match v {
-                Value::Float(f) => self.dump_f64(f)?,
-                Value::Int(i) => write!(self.writer, "{}", i)?,
-                Value::Str(s) => {
-                    self.writer.write(b"\"")?;
-                    self.writer.write(s.as_bytes())?;
-                    self.writer.write(b"\"")?;
-                }
-                Value::KeyVal((k, v)) => {
-                    let bytes = k.as_bytes();
+                Value::Float(_) | Value::Int(_) => self.dump_number(v)?,
+                Value::Str(s) => self.dump_str(s)?,
+                Value::Bool(b) => write!(self.writer, "{}", b)?,
+                Value::Nil => self.writer.write_all(b"nil")?,
+                Value::KeyVal((k, v, kind)) => {
+                    let key = key_text(k, *kind);
+                    let bytes = key.as_bytes();
                     self.writer.write(bytes)?;
                     self.writer.write(b" = ")?;
                     self.current_line_width += bytes.len() + 3;
-                    if v.is_array() {
+                    if v.is_array() && !self.comments.has_inner(&self.pos) {
                         let tlen = self.current_line_width + self.current_indent;
                         if tlen < self.max_line_width {
-                            let mut checker = LenChecker::new(self.max_line_width - tlen);
+                            let mut checker = LenChecker::new(
+                                self.max_line_width - tlen,
+                                &self.numbers,
+                                &self.pos,
+                            );
                             if checker.check(v) {
                                 self.dump_value_in_one(v)?;
                                 return Ok(());
@@ -167,8 +352,9 @@ impl Dumper {
                 }
                 Value::Array(a) => {
                     let tlen = self.current_line_width + self.current_indent;
-                    if tlen < self.max_line_width {
-                        let mut checker = LenChecker::new(self.max_line_width - tlen);
+                    if tlen < self.max_line_width && !self.comments.has_inner(&self.pos) {
+                        let mut checker =
+                            LenChecker::new(self.max_line_width - tlen, &self.numbers, &self.pos);
                         if checker.check(v) {
                             self.dump_value_in_one(v)?;
                             return Ok(());
@@ -180,12 +366,20 @@ impl Dumper {
                         if i > 0 {
                             self.writer.write(b",\n")?;
                         }
+                        self.pos.push(i);
+                        self.dump_comments(&self.pos.clone())?;
                         self.dump_indent()?;
                         self.current_line_width = 0;
                         self.dump_value(v)?;
+                        self.pos.pop();
+                    }
+                    if !a.is_empty() {
+                        self.writer.write_all(b",\n")?;
                     }
+                    self.pos.push(a.len());
+                    self.dump_comments(&self.pos.clone())?;
+                    self.pos.pop();
                     self.current_indent -= self.indent.unwrap();
-                    self.writer.write(b",\n")?;
                     self.dump_indent()?;
                     self.writer.write(b"}")?;
                 }
@@ -203,15 +397,13 @@ impl Dumper {
 
     fn dump_value_in_one(&mut self, v: &Value) -> std::io::Result<()> {
         match v {
-            Value::Float(f) => self.dump_f64(f)?,
-            Value::Int(i) => write!(self.writer, "{}", i)?,
-            Value::Str(s) => {
-                self.writer.write(b"\"")?;
-                self.writer.write(s.as_bytes())?;
-                self.writer.write(b"\"")?;
-            }
-            Value::KeyVal((k, v)) => {
-                let bytes = k.as_bytes();
+            Value::Float(_) | Value::Int(_) => self.dump_number(v)?,
+            Value::Str(s) => self.dump_str(s)?,
+            Value::Bool(b) => write!(self.writer, "{}", b)?,
+            Value::Nil => self.writer.write_all(b"nil")?,
+            Value::KeyVal((k, v, kind)) => {
+                let key = key_text(k, *kind);
+                let bytes = key.as_bytes();
                 self.writer.write(bytes)?;
                 self.writer.write(b"=")?;
                 self.dump_value_in_one(v)?;
@@ -222,7 +414,9 @@ impl Dumper {
                     if i > 0 {
                         self.writer.write(b", ")?;
                     }
+                    self.pos.push(i);
                     self.dump_value_in_one(v)?;
+                    self.pos.pop();
                 }
                 self.writer.write(b"}")?;
             }
@@ -0,0 +1,138 @@
+use encoding_rs::{Encoding, UTF_8, UTF_16BE, UTF_16LE};
+use std::borrow::Cow;
+
+/// Find encoding by its label, like `utf-8`, `shift_jis`, `cp932` or `utf-16le`
+pub fn parse_encoding(label: &str) -> Result<&'static Encoding, String> {
+    Encoding::for_label(label.as_bytes()).ok_or_else(|| format!("unknown encoding {}", label))
+}
+
+/// Code units checked by [guess_utf16]
+const UTF16_SAMPLE_UNITS: usize = 64;
+
+/// Guess encoding of UTF-16 text without BOM by the zero bytes of ASCII characters.
+/// Up to [UTF16_SAMPLE_UNITS] code units from the start are checked: the text must
+/// start with an ASCII character, at least 4 of the units (or all of them if there
+/// are fewer) must be ASCII with the zero byte on the same side, and no unit may
+/// have the zero byte on the other side or be NUL.
+fn guess_utf16(bytes: &[u8]) -> Option<&'static Encoding> {
+    let units = bytes.len().min(UTF16_SAMPLE_UNITS * 2) / 2;
+    if units == 0 {
+        return None;
+    }
+    let (mut le, mut be) = (0, 0);
+    for unit in bytes[..units * 2].chunks_exact(2) {
+        match (unit[0], unit[1]) {
+            (0, 0) => return None,
+            (a, 0) if a.is_ascii() => le += 1,
+            (0, b) if b.is_ascii() => be += 1,
+            _ => {}
+        }
+    }
+    let required = units.min(4);
+    match bytes {
+        [a, 0, ..] if a.is_ascii() && be == 0 && le >= required => Some(UTF_16LE),
+        [0, b, ..] if b.is_ascii() && le == 0 && be >= required => Some(UTF_16BE),
+        _ => None,
+    }
+}
+
+/// Detect encoding of file content. BOM is checked first, then UTF-16 without BOM
+/// and UTF-8. Other encodings like Shift-JIS are guessed from the content.
+/// Returns the encoding and the length of BOM.
+pub fn detect(bytes: &[u8]) -> (&'static Encoding, usize) {
+    if let Some((encoding, len)) = Encoding::for_bom(bytes) {
+        return (encoding, len);
+    }
+    if let Some(encoding) = guess_utf16(bytes) {
+        return (encoding, 0);
+    }
+    if std::str::from_utf8(bytes).is_ok() {
+        return (UTF_8, 0);
+    }
+    let mut detector = chardetng::EncodingDetector::new();
+    detector.feed(bytes, true);
+    (detector.guess(Some(b"jp"), true), 0)
+}
+
+/// Convert file content to UTF-8. If `encoding` is not specified, it is detected
+/// by [detect]. BOM of the encoding is converted to UTF-8 BOM, so the parser can
+/// record it. Returns the text and its encoding.
+pub fn decode(
+    bytes: Vec<u8>,
+    encoding: Option<&'static Encoding>,
+) -> anyhow::Result<(Vec<u8>, &'static Encoding)> {
+    let (encoding, bom) = match encoding {
+        Some(encoding) => match Encoding::for_bom(&bytes) {
+            Some((e, len)) if e == encoding => (encoding, len),
+            _ => (encoding, 0),
+        },
+        None => detect(&bytes),
+    };
+    if encoding == UTF_8 {
+        return Ok((bytes, encoding));
+    }
+    let text = encoding
+        .decode_without_bom_handling_and_without_replacement(&bytes[bom..])
+        .ok_or_else(|| anyhow::anyhow!("file is not valid {}", encoding.name()))?;
+    let mut result = Vec::with_capacity(text.len() + 3);
+    if bom > 0 {
+        result.extend_from_slice("\u{FEFF}".as_bytes());
+    }
+    result.extend_from_slice(text.as_bytes());
+    Ok((result, encoding))
+}
+
+/// Convert UTF-8 text to the encoding, fails if some characters can not be encoded
+pub fn encode<'a>(text: &'a str, encoding: &'static Encoding) -> std::io::Result<Cow<'a, [u8]>> {
+    if encoding == UTF_16LE {
+        return Ok(Cow::Owned(
+            text.encode_utf16().flat_map(|c| c.to_le_bytes()).collect(),
+        ));
+    } else if encoding == UTF_16BE {
+        return Ok(Cow::Owned(
+            text.encode_utf16().flat_map(|c| c.to_be_bytes()).collect(),
+        ));
+    }
+    let (bytes, _, had_errors) = encoding.encode(text);
+    if had_errors {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("text can not be encoded in {}", encoding.name()),
+        ));
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utf16(text: &str, le: bool) -> Vec<u8> {
+        text.encode_utf16()
+            .flat_map(|c| if le { c.to_le_bytes() } else { c.to_be_bytes() })
+            .collect()
+    }
+
+    #[test]
+    fn detect_utf16_without_bom() {
+        let text = "astver = 2.0\nast = {{\"テキスト\"}}\n";
+        assert_eq!(detect(&utf16(text, true)), (UTF_16LE, 0));
+        assert_eq!(detect(&utf16(text, false)), (UTF_16BE, 0));
+    }
+
+    #[test]
+    fn zero_bytes_at_start_are_not_enough() {
+        // Only the first unit looks like UTF-16
+        let bytes = b"a\0astver = 2.0\n";
+        assert_eq!(detect(bytes), (UTF_8, 0));
+        let bytes = b"\0aastver = 2.0\n";
+        assert_eq!(detect(bytes), (UTF_8, 0));
+    }
+
+    #[test]
+    fn mixed_zero_bytes_are_not_utf16() {
+        let mut bytes = utf16("astver = 2.0\n", true);
+        bytes.extend_from_slice(&utf16("ast = {}\n", false));
+        assert_eq!(guess_utf16(&bytes), None);
+    }
+}
@@ -1,4 +1,5 @@
-use crate::types::{AstFile, Message};
+use crate::text::TextSegment;
+use crate::types::{AstFile, ExtractOptions, Message, Value, Voice};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -6,30 +7,93 @@ pub struct GalTranslMessage {
     pub message: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub name: Option<String>,
+    /// Voices of the message, only exported when requested
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub voice: Option<Vec<Voice>>,
+    /// Line of the message in AST file, only exported when requested
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
 }
 
+/// Where a GalTransl message comes from in the AST file
+#[derive(Debug, Clone)]
+enum Origin {
+    SaveTitle {
+        block: String,
+        key: String,
+    },
+    Dialogue {
+        block: String,
+        lang: String,
+        index: usize,
+    },
+    Select {
+        block: String,
+        lang: String,
+        text_key: String,
+        index: usize,
+    },
+}
+
+impl Origin {
+    fn block(&self) -> &str {
+        match self {
+            Origin::SaveTitle { block, .. } => block,
+            Origin::Dialogue { block, .. } => block,
+            Origin::Select { block, .. } => block,
+        }
+    }
+
+    /// Language of dialogue or select, savetitle may use `text` instead
+    fn lang(&self) -> Option<&str> {
+        match self {
+            Origin::SaveTitle { .. } => None,
+            Origin::Dialogue { lang, .. } => Some(lang),
+            Origin::Select { lang, .. } => Some(lang),
+        }
+    }
+}
+
+/// GalTransl messages with their origins
+type OriginMessages = Vec<(GalTranslMessage, Origin)>;
+
 impl AstFile {
-    pub fn to_galtransl_json(&self, lang: Option<String>) -> anyhow::Result<String> {
+    /// Returns messages with their origins and warnings
+    fn get_galtransl_messages(
+        &self,
+        lang: Option<String>,
+        opts: &ExtractOptions,
+        voice: bool,
+    ) -> anyhow::Result<(OriginMessages, Vec<String>)> {
         let mut messages = Vec::new();
-        let mes = self.get_messages()?;
+        let mes = self.get_messages(opts)?;
         let mut lang: Option<String> = lang.clone();
         match &mes.savetitle {
             Some(title) => {
                 let title = if let Some(lang) = &lang {
-                    title.get(lang).or_else(|| title.get("text"))
+                    title
+                        .get_key_value(lang)
+                        .or_else(|| title.get_key_value("text"))
                 } else {
-                    title.first_key_value().map(|(k, v)| {
-                        if k != "text" {
+                    title.first_key_value().inspect(|(k, _)| {
+                        if *k != "text" {
                             lang = Some(k.to_string());
                         }
-                        v
                     })
                 };
-                if let Some(title) = title {
-                    messages.push(GalTranslMessage {
-                        message: title.clone(),
-                        name: None,
-                    });
+                if let (Some((key, title)), Some(block)) = (title, &mes.savetitle_block) {
+                    messages.push((
+                        GalTranslMessage {
+                            message: title.clone(),
+                            name: None,
+                            voice: None,
+                            line: None,
+                        },
+                        Origin::SaveTitle {
+                            block: block.clone(),
+                            key: key.clone(),
+                        },
+                    ));
                 }
             }
             None => {}
@@ -38,20 +102,33 @@ impl AstFile {
             match mes {
                 Message::Dialogue(d) => {
                     let d = if let Some(lang) = &lang {
-                        d.dialogues.get(lang)
+                        d.dialogues.get_key_value(lang)
                     } else {
-                        d.dialogues.first_key_value().map(|(k, v)| {
+                        d.dialogues.first_key_value().inspect(|(k, _)| {
                             lang = Some(k.to_string());
-                            v
                         })
-                    };
+                    }
+                    .map(|(k, v)| (k, v, &d.block));
                     match d {
-                        Some(d) => {
-                            for d in d {
-                                messages.push(GalTranslMessage {
-                                    message: d.text.clone(),
-                                    name: d.name.clone(),
-                                });
+                        Some((lang, d, block)) => {
+                            for (index, d) in d.iter().enumerate() {
+                                messages.push((
+                                    GalTranslMessage {
+                                        message: d.text.clone(),
+                                        name: d.name.clone(),
+                                        voice: if voice && !d.voices.is_empty() {
+                                            Some(d.voices.clone())
+                                        } else {
+                                            None
+                                        },
+                                        line: d.span.map(|s| s.line),
+                                    },
+                                    Origin::Dialogue {
+                                        block: block.clone(),
+                                        lang: lang.clone(),
+                                        index,
+                                    },
+                                ));
                             }
                         }
                         None => {}
@@ -59,20 +136,30 @@ impl AstFile {
                 }
                 Message::Select(sel) => {
                     let select = if let Some(lang) = &lang {
-                        sel.sels.get(lang)
+                        sel.sels.get_key_value(lang)
                     } else {
-                        sel.sels.first_key_value().map(|(k, v)| {
+                        sel.sels.first_key_value().inspect(|(k, _)| {
                             lang = Some(k.to_string());
-                            v
                         })
-                    };
+                    }
+                    .map(|(k, v)| (k, v, &sel.block));
                     match select {
-                        Some(sel) => {
+                        Some((lang, sel, block)) => {
                             for sel in sel {
-                                messages.push(GalTranslMessage {
-                                    message: sel.text.clone(),
-                                    name: None,
-                                });
+                                messages.push((
+                                    GalTranslMessage {
+                                        message: sel.text.clone(),
+                                        name: None,
+                                        voice: None,
+                                        line: sel.span.map(|s| s.line),
+                                    },
+                                    Origin::Select {
+                                        block: block.clone(),
+                                        lang: lang.clone(),
+                                        text_key: sel.text_key.clone(),
+                                        index: sel.index,
+                                    },
+                                ));
                             }
                         }
                         None => {}
@@ -81,10 +168,288 @@ impl AstFile {
                 _ => {}
             }
         }
+        Ok((messages, mes.warnings))
+    }
+
+    /// Convert messages to GalTransl JSON. Returns the JSON (empty if no messages) and warnings.
+    /// If `voice` is set, voices of dialogues are included in `voice` field.
+    /// If `line` is set, lines of messages in source are included in `line` field.
+    pub fn to_galtransl_json(
+        &self,
+        lang: Option<String>,
+        opts: &ExtractOptions,
+        voice: bool,
+        line: bool,
+    ) -> anyhow::Result<(String, Vec<String>)> {
+        let (messages, warnings) = self.get_galtransl_messages(lang, opts, voice)?;
+        let messages: Vec<_> = messages
+            .into_iter()
+            .map(|(mut m, _)| {
+                if !line {
+                    m.line = None;
+                }
+                m
+            })
+            .collect();
         if messages.is_empty() {
-            return Ok(String::new());
+            return Ok((String::new(), warnings));
         }
         let json = serde_json::to_string_pretty(&messages)?;
-        Ok(json)
+        Ok((json, warnings))
+    }
+
+    /// Write translated GalTransl JSON back into the AST file.
+    ///
+    /// * `lang` - Language of the exported messages, same as [AstFile::to_galtransl_json]
+    /// * `target_lang` - Language key to write translations into.
+    ///   If not specified, the source language is replaced.
+    /// * `source` - JSON exported before translating. If specified,
+    ///   every source message must be unchanged in the AST file.
+    /// * `opts` - Options used when exporting
+    ///
+    /// Returns warnings. If `lang` is not specified and `target_lang` sorts
+    /// before the exported language, a later export without `lang` picks
+    /// `target_lang`, so a warning tells which `lang` to export with.
+    pub fn import_galtransl_json(
+        &mut self,
+        json: &str,
+        lang: Option<String>,
+        target_lang: Option<String>,
+        source: Option<&str>,
+        opts: &ExtractOptions,
+    ) -> anyhow::Result<Vec<String>> {
+        let translated: Vec<GalTranslMessage> = serde_json::from_str(json)?;
+        let (messages, mut warnings) = self.get_galtransl_messages(lang.clone(), opts, false)?;
+        if let Some(source) = source {
+            let source: Vec<GalTranslMessage> = serde_json::from_str(source)?;
+            check_count(&messages, source.len(), "Source JSON")?;
+            for ((mes, origin), src) in messages.iter().zip(source.iter()) {
+                if mes.message != src.message || mes.name != src.name {
+                    return Err(anyhow::anyhow!(
+                        "Source line changed in block {}: expected {:?}, found {:?}",
+                        origin.block(),
+                        src.message,
+                        mes.message
+                    ));
+                }
+            }
+        }
+        check_count(&messages, translated.len(), "Translated JSON")?;
+        for ((_, origin), mes) in messages.iter().zip(translated.iter()) {
+            self.write_galtransl_message(origin, mes, target_lang.as_deref())?;
+        }
+        if lang.is_none()
+            && let Some(target) = &target_lang
+            && let Some(used) = messages.iter().find_map(|(_, o)| o.lang())
+            && target.as_str() < used
+        {
+            warnings.push(format!(
+                "Language {} is now found before {}, use --lang {} to export the same messages again",
+                target, used, used
+            ));
+        }
+        Ok(warnings)
+    }
+
+    fn write_galtransl_message(
+        &mut self,
+        origin: &Origin,
+        mes: &GalTranslMessage,
+        target_lang: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let name = origin.block();
+        let block = self
+            .get_block_mut(name)
+            .ok_or(anyhow::anyhow!("Can not find block {}", name))?;
+        match origin {
+            Origin::SaveTitle { key, .. } => {
+                let title = block
+                    .find_array_attrs_mut("savetitle")
+                    .into_iter()
+                    .next()
+                    .ok_or(anyhow::anyhow!("Can not get savetitle from block {}", name))?;
+                title.set_keyval(target_lang.unwrap_or(key), Value::Str(mes.message.clone()));
+            }
+            Origin::Dialogue { lang, index, .. } => {
+                let text = block
+                    .find_keyval_mut("text")
+                    .ok_or(anyhow::anyhow!("Can not get text from block {}", name))?;
+                let text = get_lang_mut(text, lang, target_lang.unwrap_or(lang))
+                    .ok_or(anyhow::anyhow!("Can not get text from block {}", name))?;
+                let dialogue = text.get_member_mut(*index).ok_or(anyhow::anyhow!(
+                    "Can not get dialogue {} from block {}",
+                    index,
+                    name
+                ))?;
+                set_dialogue(dialogue, mes)
+                    .map_err(|e| anyhow::anyhow!("Failed to write block {}: {}", name, e))?;
+            }
+            Origin::Select {
+                lang,
+                text_key,
+                index,
+                ..
+            } => {
+                let text = block.find_keyval_mut(text_key).ok_or(anyhow::anyhow!(
+                    "Can not get text block {} from select block {}",
+                    text_key,
+                    name
+                ))?;
+                let text = get_lang_mut(text, lang, target_lang.unwrap_or(lang)).ok_or(
+                    anyhow::anyhow!("Can not get text from select block {}", name),
+                )?;
+                let sel = text.get_member_mut(*index).ok_or(anyhow::anyhow!(
+                    "Can not get text from select block {}",
+                    name
+                ))?;
+                *sel = Value::Str(mes.message.clone());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn check_count(
+    messages: &[(GalTranslMessage, Origin)],
+    count: usize,
+    what: &str,
+) -> anyhow::Result<()> {
+    if messages.len() > count {
+        return Err(anyhow::anyhow!(
+            "{} has {} messages but {} are extracted, missing messages from block {}",
+            what,
+            count,
+            messages.len(),
+            messages[count].1.block()
+        ));
+    } else if messages.len() < count {
+        return Err(anyhow::anyhow!(
+            "{} has {} messages but {} are extracted, extra messages after block {}",
+            what,
+            count,
+            messages.len(),
+            messages.last().map_or("(none)", |(_, o)| o.block())
+        ));
+    }
+    Ok(())
+}
+
+/// Get the `target` language member of `text`.
+/// If it not exists, it is cloned from the `lang` language member.
+fn get_lang_mut<'a>(text: &'a mut Value, lang: &str, target: &str) -> Option<&'a mut Value> {
+    if text.find_keyval(target).is_none() && !text.clone_keyval(lang, target) {
+        return None;
+    }
+    text.find_keyval_mut(target)
+}
+
+fn set_dialogue(dialogue: &mut Value, mes: &GalTranslMessage) -> anyhow::Result<()> {
+    let arr = match dialogue {
+        Value::Array(arr) => arr,
+        _ => return Err(anyhow::anyhow!("dialogue is not an array")),
+    };
+    let template = TextSegment::parse(arr)?;
+    let segments = TextSegment::from_html(&mes.message, &template)?;
+    let mut result: Vec<Value> = TextSegment::keyvals(&template)
+        .into_iter()
+        .cloned()
+        .collect();
+    if let (Some(name), Some(Value::Array(names))) = (
+        &mes.name,
+        result.iter_mut().find_map(|v| v.find_keyval_mut("name")),
+    ) && let Some(last) = names.last_mut()
+    {
+        *last = Value::Str(name.clone());
+    }
+    result.extend(TextSegment::to_values(&segments));
+    *arr = result;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    const SRC: &str = include_str!("../tests/roundtrip/sample.ast");
+
+    fn parse() -> AstFile {
+        Parser::new(&SRC).parse().unwrap()
+    }
+
+    fn export(ast: &AstFile) -> String {
+        let opts = ExtractOptions::default();
+        ast.to_galtransl_json(None, &opts, false, false).unwrap().0
+    }
+
+    /// Exported JSON with every message replaced by `訳` and its index
+    fn translate(json: &str) -> Vec<GalTranslMessage> {
+        let mut messages: Vec<GalTranslMessage> = serde_json::from_str(json).unwrap();
+        for (i, m) in messages.iter_mut().enumerate() {
+            m.message = format!("訳{}", i);
+        }
+        messages
+    }
+
+    fn lang<'a>(ast: &'a AstFile, block: &str, key: &str, lang: &str) -> Option<&'a Value> {
+        ast.ast
+            .find_keyval(block)?
+            .find_keyval(key)?
+            .find_keyval(lang)
+    }
+
+    #[test]
+    fn import_exported_keeps_file() {
+        let mut ast = parse();
+        let json = export(&ast);
+        let opts = ExtractOptions::default();
+        ast.import_galtransl_json(&json, None, None, Some(&json), &opts)
+            .unwrap();
+        assert_eq!(ast.ast, parse().ast);
+    }
+
+    #[test]
+    fn import_target_lang_keeps_source() {
+        let mut ast = parse();
+        let json = export(&ast);
+        let translated = serde_json::to_string(&translate(&json)).unwrap();
+        let opts = ExtractOptions::default();
+        let warnings = ast
+            .import_galtransl_json(&translated, None, Some("cn".into()), Some(&json), &opts)
+            .unwrap();
+        let orig = parse();
+        for (block, key) in [
+            ("block_00000", "text"),
+            ("block_00001", "text"),
+            ("block_00002", "select_1"),
+        ] {
+            assert_eq!(lang(&ast, block, key, "ja"), lang(&orig, block, key, "ja"));
+        }
+        let cn = lang(&ast, "block_00001", "text", "cn").unwrap();
+        assert_eq!(
+            cn.get_member(0).unwrap().get_member(0).unwrap().as_str(),
+            Some("訳2")
+        );
+        let select = lang(&ast, "block_00002", "select_1", "cn").unwrap();
+        assert_eq!(select.get_member(1).unwrap().as_str(), Some("訳4"));
+        assert!(
+            warnings.iter().any(|w| w.contains("--lang ja")),
+            "{:?}",
+            warnings
+        );
+    }
+
+    #[test]
+    fn import_count_mismatch_names_block() {
+        let mut ast = parse();
+        let mut messages = translate(&export(&ast));
+        messages.pop();
+        let translated = serde_json::to_string(&messages).unwrap();
+        let opts = ExtractOptions::default();
+        let err = ast
+            .import_galtransl_json(&translated, None, None, None, &opts)
+            .unwrap_err();
+        assert!(err.to_string().contains("block_00002"), "{}", err);
+        assert_eq!(ast.ast, parse().ast);
     }
 }
@@ -0,0 +1,365 @@
+use crate::types::{AstFile, Span, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+
+/// A block in a scenario, identified by file name (without directory and `.ast`,
+/// so files must have different names) and block name
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeId {
+    pub file: String,
+    pub block: String,
+}
+
+impl NodeId {
+    pub fn new<F: Into<String>, B: Into<String>>(file: F, block: B) -> Self {
+        NodeId {
+            file: file.into(),
+            block: block.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for NodeId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.file, self.block)
+    }
+}
+
+impl std::str::FromStr for NodeId {
+    type Err = anyhow::Error;
+
+    /// Parse `file:block`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (file, block) = s
+            .split_once(':')
+            .ok_or(anyhow::anyhow!("invalid block {}, expected FILE:BLOCK", s))?;
+        Ok(NodeId::new(file, block))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Node {
+    pub line: Option<i64>,
+    /// Labels pointing to the block
+    pub labels: Vec<String>,
+    /// Where the block is in source
+    pub span: Option<Span>,
+}
+
+#[derive(Clone, Debug)]
+pub enum EdgeKind {
+    LinkNext,
+    /// A choice of select, `texts` are the choice texts by language
+    Select {
+        texts: BTreeMap<String, String>,
+    },
+    ExCall,
+}
+
+#[derive(Clone, Debug)]
+pub struct Edge {
+    pub from: NodeId,
+    pub kind: EdgeKind,
+    /// Target file, same as `from` if not specified in block
+    pub file: String,
+    /// Target block of `linknext`
+    pub block: Option<String>,
+    /// Target label of select and excall, `top` if not specified
+    pub label: Option<String>,
+    /// Target node, None if the target can not be resolved
+    pub to: Option<NodeId>,
+}
+
+/// Graph of blocks connected by `linknext`, select choices and excalls across files
+#[derive(Debug, Default)]
+pub struct ScenarioGraph {
+    nodes: BTreeMap<NodeId, Node>,
+    edges: Vec<Edge>,
+    /// Label to block name by file
+    labels: HashMap<String, HashMap<String, String>>,
+    outgoing: HashMap<NodeId, Vec<usize>>,
+    incoming: HashMap<NodeId, Vec<usize>>,
+}
+
+/// Convert file name in select and excall to the name used by [NodeId]
+pub fn file_key(file: &str) -> String {
+    let path = std::path::Path::new(file);
+    match path.file_stem() {
+        Some(stem) if path.extension().is_some_and(|e| e == "ast") => {
+            stem.to_string_lossy().to_string()
+        }
+        _ => file.to_string(),
+    }
+}
+
+impl ScenarioGraph {
+    /// Build graph from files. Names of files are used as [NodeId::file].
+    pub fn new<'a, I: IntoIterator<Item = (&'a str, &'a AstFile)>>(files: I) -> Self {
+        let mut graph = ScenarioGraph::default();
+        for (name, ast) in files {
+            graph.add_file(name, ast);
+        }
+        graph.resolve();
+        graph
+    }
+
+    fn add_file(&mut self, name: &str, ast: &AstFile) {
+        let blocks = ast.get_blocks();
+        let mut labels = HashMap::new();
+        let mut block_labels = HashMap::<&str, Vec<String>>::new();
+        if let Some(Value::Array(arr)) = blocks.get("label").map(|v| v.as_ref()) {
+            for v in arr {
+                if let Value::KeyVal((k, v, _)) = v
+                    && let Some(block) = v.find_keyval("block").and_then(|v| v.as_str())
+                {
+                    labels.insert(k.clone(), block.to_string());
+                    block_labels.entry(block).or_default().push(k.clone());
+                }
+            }
+        }
+        let mut names: Vec<&String> = blocks.keys().collect();
+        names.sort();
+        for k in names {
+            let block = &blocks[k];
+            if k == "label" {
+                continue;
+            }
+            let id = NodeId::new(name, k.as_str());
+            if let Some(next) = block.find_keyval("linknext").and_then(|v| v.as_str()) {
+                self.edges.push(Edge {
+                    from: id.clone(),
+                    kind: EdgeKind::LinkNext,
+                    file: name.to_string(),
+                    block: Some(next.to_string()),
+                    label: None,
+                    to: None,
+                });
+            }
+            let mut used = HashMap::<&str, usize>::new();
+            for select in block.find_array_attrs("select") {
+                let mut texts = BTreeMap::new();
+                if let Some(text) = select.find_keyval("text").and_then(|v| v.as_str()) {
+                    let count = used.entry(text).or_insert(0);
+                    if let Some(Value::Array(arr)) = block.find_keyval(text) {
+                        for v in arr {
+                            if let Value::KeyVal((lang, v, _)) = v
+                                && let Some(t) = v.get_member(*count).and_then(|v| v.as_str())
+                            {
+                                texts.insert(lang.clone(), t.to_string());
+                            }
+                        }
+                    }
+                    *count += 1;
+                }
+                self.edges
+                    .push(self.target_edge(&id, select, EdgeKind::Select { texts }));
+            }
+            for excall in block.find_array_attrs("excall") {
+                self.edges
+                    .push(self.target_edge(&id, excall, EdgeKind::ExCall));
+            }
+            self.nodes.insert(
+                id,
+                Node {
+                    line: block.find_keyval("line").and_then(|v| v.as_int()),
+                    labels: block_labels.get(k.as_str()).cloned().unwrap_or_default(),
+                    span: ast.block_span(k),
+                },
+            );
+        }
+        self.labels.insert(name.to_string(), labels);
+    }
+
+    fn target_edge(&self, from: &NodeId, v: &Value, kind: EdgeKind) -> Edge {
+        Edge {
+            from: from.clone(),
+            kind,
+            file: v
+                .find_keyval("file")
+                .and_then(|v| v.as_str())
+                .map_or_else(|| from.file.clone(), file_key),
+            block: None,
+            label: v
+                .find_keyval("label")
+                .and_then(|v| v.as_str())
+                .map(|v| v.to_string()),
+            to: None,
+        }
+    }
+
+    fn resolve(&mut self) {
+        for (i, edge) in self.edges.iter_mut().enumerate() {
+            let block = match &edge.block {
+                Some(block) => Some(block.as_str()),
+                None => self
+                    .labels
+                    .get(&edge.file)
+                    .and_then(|l| l.get(edge.label.as_deref().unwrap_or("top")))
+                    .map(|b| b.as_str()),
+            };
+            edge.to = block
+                .map(|b| NodeId::new(edge.file.as_str(), b))
+                .filter(|id| self.nodes.contains_key(id));
+            self.outgoing.entry(edge.from.clone()).or_default().push(i);
+            if let Some(to) = &edge.to {
+                self.incoming.entry(to.clone()).or_default().push(i);
+            }
+        }
+    }
+
+    pub fn nodes(&self) -> &BTreeMap<NodeId, Node> {
+        &self.nodes
+    }
+
+    /// Names of files in the graph
+    pub fn files(&self) -> Vec<&str> {
+        let mut files: Vec<_> = self.labels.keys().map(|f| f.as_str()).collect();
+        files.sort();
+        files
+    }
+
+    /// Labels of the file, label name to block name
+    pub fn labels(&self, file: &str) -> Option<&HashMap<String, String>> {
+        self.labels.get(file)
+    }
+
+    /// The block pointed by `top` label of the file
+    pub fn top(&self, file: &str) -> Option<NodeId> {
+        self.labels
+            .get(file)
+            .and_then(|l| l.get("top"))
+            .map(|b| NodeId::new(file, b.as_str()))
+            .filter(|id| self.nodes.contains_key(id))
+    }
+
+    /// Edges from the block, in the order of `linknext`, selects and excalls
+    pub fn successors(&self, id: &NodeId) -> Vec<&Edge> {
+        self.outgoing
+            .get(id)
+            .map(|v| v.iter().map(|i| &self.edges[*i]).collect())
+            .unwrap_or_default()
+    }
+
+    /// Edges pointing to the block
+    pub fn predecessors(&self, id: &NodeId) -> Vec<&Edge> {
+        self.incoming
+            .get(id)
+            .map(|v| v.iter().map(|i| &self.edges[*i]).collect())
+            .unwrap_or_default()
+    }
+
+    /// Blocks reachable from `starts`, including themselves
+    pub fn reachable<'a, I: IntoIterator<Item = &'a NodeId>>(&self, starts: I) -> BTreeSet<NodeId> {
+        let mut visited = BTreeSet::new();
+        let mut stack: Vec<NodeId> = starts.into_iter().cloned().collect();
+        while let Some(id) = stack.pop() {
+            if !self.nodes.contains_key(&id) || !visited.insert(id.clone()) {
+                continue;
+            }
+            for edge in self.successors(&id) {
+                if let Some(to) = &edge.to {
+                    stack.push(to.clone());
+                }
+            }
+        }
+        visited
+    }
+
+    /// Shortest path from `from` to `to`, including both ends
+    pub fn shortest_path(&self, from: &NodeId, to: &NodeId) -> Option<Vec<NodeId>> {
+        let mut prev = HashMap::<NodeId, NodeId>::new();
+        let mut queue = VecDeque::new();
+        let mut visited = HashSet::new();
+        visited.insert(from.clone());
+        queue.push_back(from.clone());
+        while let Some(id) = queue.pop_front() {
+            if &id == to {
+                let mut path = vec![id];
+                while let Some(p) = prev.get(path.last().unwrap()) {
+                    path.push(p.clone());
+                }
+                path.reverse();
+                return Some(path);
+            }
+            for edge in self.successors(&id) {
+                if let Some(next) = &edge.to
+                    && visited.insert(next.clone())
+                {
+                    prev.insert(next.clone(), id.clone());
+                    queue.push_back(next.clone());
+                }
+            }
+        }
+        None
+    }
+
+    /// Shortest path from the top of the block's file to the block
+    pub fn path_from_top(&self, id: &NodeId) -> Option<Vec<NodeId>> {
+        self.shortest_path(&self.top(&id.file)?, id)
+    }
+
+    /// Block names of the file reachable from top without leaving the file.
+    /// The `linknext` chain from top comes first, followed by the chains
+    /// starting from select and excall targets in the order they are found.
+    pub fn block_order(&self, file: &str) -> Vec<String> {
+        let mut order = Vec::new();
+        let mut visited = HashSet::new();
+        let mut queue: VecDeque<NodeId> = self.top(file).into_iter().collect();
+        while let Some(start) = queue.pop_front() {
+            let mut cur = Some(start);
+            while let Some(id) = cur.take() {
+                if !visited.insert(id.clone()) {
+                    break;
+                }
+                for edge in self.successors(&id) {
+                    match (&edge.kind, &edge.to) {
+                        (EdgeKind::LinkNext, Some(to)) => cur = Some(to.clone()),
+                        (_, Some(to)) if to.file == file => queue.push_back(to.clone()),
+                        _ => {}
+                    }
+                }
+                order.push(id.block);
+            }
+        }
+        order
+    }
+
+    /// Blocks of the file collapsed into runs. A run is a chain of blocks
+    /// connected by `linknext` where no other edge leaves or enters the chain
+    /// and no label points into it. Runs are ordered by the line of their first block.
+    pub fn runs(&self, file: &str) -> Vec<Vec<NodeId>> {
+        let mut ids: Vec<&NodeId> = self.nodes.keys().filter(|id| id.file == file).collect();
+        ids.sort_by_key(|id| (self.nodes[*id].line, *id));
+        let next_in_run = |id: &NodeId| -> Option<NodeId> {
+            let succ = self.successors(id);
+            match (succ.len(), succ.first().map(|e| (&e.kind, &e.to))) {
+                (1, Some((EdgeKind::LinkNext, Some(to))))
+                    if to.file == file
+                        && self.predecessors(to).len() == 1
+                        && self.nodes[to].labels.is_empty() =>
+                {
+                    Some(to.clone())
+                }
+                _ => None,
+            }
+        };
+        let inner: HashSet<NodeId> = ids.iter().filter_map(|id| next_in_run(id)).collect();
+        let mut covered = HashSet::new();
+        let mut runs = Vec::new();
+        // Blocks which are not the head of any run are only left in cycles
+        let heads = ids.iter().filter(|id| !inner.contains(**id));
+        for id in heads.chain(ids.iter()) {
+            if !covered.insert((*id).clone()) {
+                continue;
+            }
+            let mut run = vec![(*id).clone()];
+            while let Some(next) = next_in_run(run.last().unwrap()) {
+                if !covered.insert(next.clone()) {
+                    break;
+                }
+                run.push(next);
+            }
+            runs.push(run);
+        }
+        runs
+    }
+}
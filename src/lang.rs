@@ -0,0 +1,50 @@
+use crate::types::{AstFile, Value};
+
+impl AstFile {
+    /// Add a new language to all text blocks, select texts and savetitles
+    /// by cloning the `lang` language, including names and inline tags.
+    /// Existing `new_lang` members are kept unless `overwrite` is set.
+    ///
+    /// The new language can be filled later by [AstFile::import_galtransl_json]
+    /// with `new_lang` as target language.
+    ///
+    /// Returns the number of added members.
+    pub fn add_language(&mut self, lang: &str, new_lang: &str, overwrite: bool) -> usize {
+        let mut count = 0;
+        let mut add = |v: &mut Value| {
+            if (overwrite || v.find_keyval(new_lang).is_none()) && v.clone_keyval(lang, new_lang) {
+                count += 1;
+            }
+        };
+        let blocks = match &mut self.ast {
+            Value::Array(arr) => arr,
+            _ => return 0,
+        };
+        for block in blocks.iter_mut() {
+            let block = match block {
+                Value::KeyVal((_, v, _)) => v.as_mut(),
+                _ => continue,
+            };
+            if let Some(text) = block.find_keyval_mut("text") {
+                add(text);
+            }
+            let mut keys = Vec::new();
+            for select in block.find_array_attrs("select") {
+                if let Some(key) = select.find_keyval("text").and_then(|v| v.as_str())
+                    && !keys.iter().any(|k| k == key)
+                {
+                    keys.push(key.to_string());
+                }
+            }
+            for key in keys {
+                if let Some(text) = block.find_keyval_mut(&key) {
+                    add(text);
+                }
+            }
+            for title in block.find_array_attrs_mut("savetitle") {
+                add(title);
+            }
+        }
+        count
+    }
+}
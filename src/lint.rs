@@ -0,0 +1,175 @@
+use crate::graph::{EdgeKind, NodeId, ScenarioGraph};
+use crate::types::{AstFile, Span, Value};
+use std::collections::{BTreeMap, HashSet};
+
+#[derive(Clone, Debug)]
+pub enum Issue {
+    /// No linknext, label, select or excall points to the block
+    Orphan(NodeId),
+    /// The block is pointed by others but can not be reached from the top of any file
+    Unreachable(NodeId),
+    /// A label points to a block which does not exist
+    MissingLabelTarget {
+        file: String,
+        label: String,
+        block: String,
+    },
+    /// `linknext` points to a block which does not exist
+    MissingLinkNext { from: NodeId, block: String },
+    /// Multiple blocks have the same name, only the one with the largest line is used
+    DuplicateBlock {
+        file: String,
+        block: String,
+        lines: Vec<Option<i64>>,
+    },
+    /// The file or label of select or excall can not be found in scanned files
+    UnresolvedTarget {
+        from: NodeId,
+        kind: &'static str,
+        file: String,
+        label: String,
+    },
+}
+
+impl std::fmt::Display for Issue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Issue::Orphan(id) => write!(f, "{}: block is not referenced by anything", id),
+            Issue::Unreachable(id) => write!(f, "{}: block is unreachable from top", id),
+            Issue::MissingLabelTarget { file, label, block } => write!(
+                f,
+                "{}: label {} points to missing block {}",
+                file, label, block
+            ),
+            Issue::MissingLinkNext { from, block } => {
+                write!(f, "{}: linknext points to missing block {}", from, block)
+            }
+            Issue::DuplicateBlock { file, block, lines } => {
+                let lines: Vec<String> = lines
+                    .iter()
+                    .map(|l| l.map_or("?".to_string(), |l| l.to_string()))
+                    .collect();
+                write!(
+                    f,
+                    "{}:{}: duplicate block at lines {}",
+                    file,
+                    block,
+                    lines.join(", ")
+                )
+            }
+            Issue::UnresolvedTarget {
+                from,
+                kind,
+                file,
+                label,
+            } => write!(f, "{}: {} target {}#{} not found", from, kind, file, label),
+        }
+    }
+}
+
+impl AstFile {
+    /// Block names defined more than once, with the line of every definition
+    pub fn duplicate_blocks(&self) -> BTreeMap<String, Vec<Option<i64>>> {
+        let mut blocks = BTreeMap::<String, Vec<Option<i64>>>::new();
+        if let Value::Array(arr) = &self.ast {
+            for v in arr {
+                if let Value::KeyVal((k, v, _)) = v {
+                    let line = v.find_keyval("line").and_then(|v| v.as_int());
+                    blocks.entry(k.clone()).or_default().push(line);
+                }
+            }
+        }
+        blocks.retain(|_, lines| lines.len() > 1);
+        blocks
+    }
+}
+
+/// Check files for broken references and blocks which can never be shown.
+/// Issues are grouped by file in the order of `files`, with where they are in source.
+pub fn lint(files: &[(String, AstFile)]) -> Vec<(Issue, Option<Span>)> {
+    let graph = ScenarioGraph::new(files.iter().map(|(name, ast)| (name.as_str(), ast)));
+    let tops: Vec<NodeId> = graph
+        .files()
+        .into_iter()
+        .filter_map(|f| graph.top(f))
+        .collect();
+    let reachable = graph.reachable(tops.iter());
+    let mut issues = Vec::new();
+    let mut seen = HashSet::new();
+    for (name, ast) in files {
+        if !seen.insert(name.as_str()) {
+            continue;
+        }
+        for (block, lines) in ast.duplicate_blocks() {
+            let span = ast.block_span(&block);
+            issues.push((
+                Issue::DuplicateBlock {
+                    file: name.clone(),
+                    block,
+                    lines,
+                },
+                span,
+            ));
+        }
+        let label_block = ast.block_index("label");
+        if let Some(labels) = graph.labels(name) {
+            let mut labels: Vec<_> = labels.iter().collect();
+            labels.sort();
+            for (label, block) in labels {
+                if !graph
+                    .nodes()
+                    .contains_key(&NodeId::new(name.as_str(), block.as_str()))
+                {
+                    let span = label_block.and_then(|i| {
+                        let j = ast
+                            .ast
+                            .get_member(i)?
+                            .find_keyval("label")?
+                            .keyval_index(label)?;
+                        ast.span(&[i, j])
+                    });
+                    issues.push((
+                        Issue::MissingLabelTarget {
+                            file: name.clone(),
+                            label: label.clone(),
+                            block: block.clone(),
+                        },
+                        span,
+                    ));
+                }
+            }
+        }
+        for (id, node) in graph.nodes().range(NodeId::new(name.as_str(), "")..) {
+            if &id.file != name {
+                break;
+            }
+            for edge in graph.successors(id) {
+                if edge.to.is_some() {
+                    continue;
+                }
+                let issue = match &edge.kind {
+                    EdgeKind::LinkNext => Issue::MissingLinkNext {
+                        from: id.clone(),
+                        block: edge.block.clone().unwrap_or_default(),
+                    },
+                    kind => Issue::UnresolvedTarget {
+                        from: id.clone(),
+                        kind: match kind {
+                            EdgeKind::Select { .. } => "select",
+                            _ => "excall",
+                        },
+                        file: edge.file.clone(),
+                        label: edge.label.clone().unwrap_or_else(|| "top".to_string()),
+                    },
+                };
+                issues.push((issue, node.span));
+            }
+            if node.labels.is_empty() && graph.predecessors(id).is_empty() {
+                issues.push((Issue::Orphan(id.clone()), node.span));
+            } else if !reachable.contains(id) {
+                issues.push((Issue::Unreachable(id.clone()), node.span));
+            }
+        }
+    }
+    issues
+}
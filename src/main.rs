@@ -2,23 +2,114 @@ use clap::Parser;
 use std::io::Write;
 
 mod args;
+mod diff;
 mod dump;
+mod encoding;
 mod galtransl;
+mod graph;
+mod lang;
+mod lint;
+mod merge;
 mod parser;
 mod render;
+mod text;
 mod types;
 mod utils;
+mod verify;
 
-fn fmt_file(f: &str, args: &args::Arg, sort_blocks: bool) -> anyhow::Result<()> {
+/// Read AST file and convert it to UTF-8, returns the text and original encoding
+fn read_source(
+    f: &str,
+    args: &args::Arg,
+) -> anyhow::Result<(Vec<u8>, &'static encoding_rs::Encoding)> {
     let content = utils::read_file(f)?;
-    let parser = parser::Parser::new(&content);
+    encoding::decode(content, args.encoding)
+}
+
+/// Read and parse AST file
+fn parse_file(f: &str, args: &args::Arg) -> anyhow::Result<types::AstFile> {
+    let (content, encoding) = read_source(f, args)?;
+    let mut parser = parser::Parser::new(&content);
+    parser.set_keep_comments(args.keep_comments);
+    parser.set_keep_spans(true);
     let mut ast = parser.parse()?;
+    ast.encoding = encoding;
+    Ok(ast)
+}
+
+/// Parse UTF-8 text of file with error recovery, so every broken spot is reported at once
+fn parse_recover(
+    content: &[u8],
+    encoding: &'static encoding_rs::Encoding,
+    args: &args::Arg,
+) -> anyhow::Result<(types::AstFile, Vec<parser::Diagnostic>)> {
+    let mut parser = parser::Parser::new(&content);
+    parser.set_keep_comments(args.keep_comments);
+    let (mut ast, diagnostics) = parser.parse_recover()?;
+    ast.encoding = encoding;
+    Ok((ast, diagnostics))
+}
+
+/// Format file, returns whether it is changed. In check mode,
+/// the file is not written and the diff is printed instead.
+fn fmt_file(f: &str, args: &args::Arg, sort_blocks: bool, check: bool) -> anyhow::Result<bool> {
+    let original = utils::read_file(f)?;
+    let (content, encoding) = encoding::decode(original.clone(), args.encoding)?;
+    let (mut ast, diagnostics) = parse_recover(&content, encoding, args)?;
+    if !diagnostics.is_empty() {
+        for d in diagnostics.iter() {
+            eprintln!("{}: {}", f, d);
+        }
+        anyhow::bail!("{} parse errors, file is not written", diagnostics.len());
+    }
     if sort_blocks {
         ast.sort_blocks();
     }
-    let f = utils::write_file(f)?;
-    let f = std::io::BufWriter::new(f);
-    let mut dumper = dump::Dumper::new(f);
+    let text = dump_text(&ast, args)?;
+    let output = encode_output(&ast, args, text.clone())?;
+    if output == original && f != "-" {
+        return Ok(false);
+    }
+    if check {
+        let old = String::from_utf8_lossy(&content);
+        let new = String::from_utf8_lossy(&text);
+        if old == new {
+            println!("{}: encoding would change", f);
+        } else {
+            let diff = similar::TextDiff::from_lines(old.as_ref(), new.as_ref());
+            print!(
+                "{}",
+                diff.unified_diff()
+                    .header(&format!("{} (original)", f), &format!("{} (formatted)", f))
+            );
+        }
+        return Ok(true);
+    }
+    warn_dropped_comments(f, &ast);
+    utils::write_file_atomic(f, &output, args.backup.as_deref())?;
+    Ok(true)
+}
+
+/// Parse file, dump it and parse the dump again, returns the differences between them
+fn verify_file(f: &str, args: &args::Arg) -> anyhow::Result<Vec<verify::Divergence>> {
+    let (content, encoding) = read_source(f, args)?;
+    let mut parser = parser::Parser::new(&content);
+    parser.set_keep_comments(args.keep_comments);
+    let mut ast = parser.parse()?;
+    ast.encoding = encoding;
+    let text = dump_text(&ast, args)?;
+    let mut parser = parser::Parser::new(&text);
+    parser.set_keep_comments(args.keep_comments);
+    let dumped = parser
+        .parse()
+        .map_err(|e| anyhow::anyhow!("Failed to parse dumped file: {}", e))?;
+    Ok(verify::compare(&ast, &dumped))
+}
+
+/// Dump AST file to UTF-8 text
+fn dump_text(ast: &types::AstFile, args: &args::Arg) -> anyhow::Result<Vec<u8>> {
+    let mut text = Vec::new();
+    let mut dumper = dump::Dumper::new(&mut text);
     if args.no_indent {
         dumper.set_no_indent();
     } else {
@@ -27,15 +118,207 @@ fn fmt_file(f: &str, args: &args::Arg, sort_blocks: bool) -> anyhow::Result<()>
     if let Some(max_line_width) = args.max_line_width {
         dumper.set_max_line_width(max_line_width);
     }
-    dumper.dump(&ast)?;
+    dumper.set_normalize(args.normalize);
+    dumper.dump(ast)?;
+    Ok(text)
+}
+
+/// Convert dumped text to the original encoding of file if `--keep-encoding` is set
+fn encode_output(ast: &types::AstFile, args: &args::Arg, text: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    if args.keep_encoding && ast.encoding != encoding_rs::UTF_8 {
+        let text = String::from_utf8(text)?;
+        return Ok(encoding::encode(&text, ast.encoding)?.into_owned());
+    }
+    Ok(text)
+}
+
+/// Warn if comments of file are dropped because `--keep-comments` is not set
+fn warn_dropped_comments(f: &str, ast: &types::AstFile) {
+    if ast.comments.dropped > 0 {
+        eprintln!(
+            "Warning: {}: {} comments are dropped, use --keep-comments to keep them",
+            f, ast.comments.dropped
+        );
+    }
+}
+
+fn dump_file(ast: &types::AstFile, f: &str, args: &args::Arg) -> anyhow::Result<()> {
+    warn_dropped_comments(f, ast);
+    let output = encode_output(ast, args, dump_text(ast, args)?)?;
+    utils::write_file_atomic(f, &output, args.backup.as_deref())?;
     Ok(())
 }
 
-fn to_json(f: &str, output: &str, lang: Option<String>) -> anyhow::Result<bool> {
-    let content = utils::read_file(f)?;
-    let parser = parser::Parser::new(&content);
-    let ast = parser.parse()?;
-    let output_json = ast.to_galtransl_json(lang)?;
+fn tag_policy(args: &args::Arg) -> anyhow::Result<text::TagPolicy> {
+    let mut policy = text::TagPolicy::default();
+    policy.set_unknown_mode(match args.unknown_tags {
+        args::UnknownTags::Error => text::UnknownTagMode::Error,
+        args::UnknownTags::Placeholder => text::UnknownTagMode::Placeholder,
+        args::UnknownTags::Ignore => text::UnknownTagMode::Ignore,
+    });
+    for tag in args.tags.iter() {
+        let (name, render) = tag
+            .split_once('=')
+            .ok_or(anyhow::anyhow!("Invalid tag {}, expected NAME=RENDER", tag))?;
+        policy.set_tag(name, render.parse()?);
+    }
+    Ok(policy)
+}
+
+fn extract_options(
+    f: &str,
+    ast: &types::AstFile,
+    args: &args::Arg,
+) -> anyhow::Result<types::ExtractOptions> {
+    let mut opts = types::ExtractOptions {
+        policy: tag_policy(args)?,
+        order: None,
+    };
+    if args.follow_branches {
+        let name = graph::file_key(
+            &std::path::Path::new(f)
+                .file_name()
+                .map_or_else(|| f.to_string(), |n| n.to_string_lossy().to_string()),
+        );
+        let graph = graph::ScenarioGraph::new([(name.as_str(), ast)]);
+        opts.order = Some(graph.block_order(&name));
+    }
+    Ok(opts)
+}
+
+/// Parse files with their names used by graph. Files failed to parse are
+/// reported and skipped, returns the parsed files and the count of failed files.
+/// Links name files without directory, so files with the same name are an error.
+fn load_asts(
+    files: &Vec<String>,
+    args: &args::Arg,
+) -> anyhow::Result<(Vec<(String, types::AstFile)>, usize)> {
+    let files = utils::collect_ast_files(files, args.recursive)?;
+    let mut seen = std::collections::HashMap::new();
+    let mut names = Vec::new();
+    for f in files.iter() {
+        let name = std::path::Path::new(f)
+            .file_stem()
+            .map_or_else(|| f.clone(), |n| n.to_string_lossy().to_string());
+        names.push((name.clone(), f));
+        if let Some(other) = seen.insert(name.clone(), f) {
+            return Err(anyhow::anyhow!(
+                "Files {} and {} have the same name {}",
+                other,
+                f,
+                name
+            ));
+        }
+    }
+    let mut asts = Vec::new();
+    let mut failed = 0;
+    for (name, f) in names {
+        match parse_file(f, args) {
+            Ok(ast) => {
+                asts.push((name, ast));
+            }
+            Err(e) => {
+                eprintln!("Error parsing file {}: {}", f, e);
+                failed += 1;
+            }
+        }
+    }
+    Ok((asts, failed))
+}
+
+/// Parse files and build scenario graph, returns the graph and the count of failed files
+fn load_graph(
+    files: &Vec<String>,
+    args: &args::Arg,
+) -> anyhow::Result<(graph::ScenarioGraph, usize)> {
+    let (asts, failed) = load_asts(files, args)?;
+    let graph = graph::ScenarioGraph::new(asts.iter().map(|(name, ast)| (name.as_str(), ast)));
+    Ok((graph, failed))
+}
+
+/// Result of a step which stops the whole command, prints the error and exits on error
+fn or_exit<T>(result: anyhow::Result<T>) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    })
+}
+
+/// Exit with error if some files failed to parse, see [load_asts]
+fn exit_if_failed(failed: usize) {
+    if failed != 0 {
+        eprintln!("Failed to parse {} files", failed);
+        std::process::exit(1);
+    }
+}
+
+fn describe_edge(edge: &graph::Edge) -> String {
+    let target = match &edge.block {
+        Some(block) => format!("{}:{}", edge.file, block),
+        None => format!("{}#{}", edge.file, edge.label.as_deref().unwrap_or("top")),
+    };
+    let kind = match &edge.kind {
+        graph::EdgeKind::LinkNext => "linknext".to_string(),
+        graph::EdgeKind::Select { texts } => match texts.first_key_value() {
+            Some((_, t)) => format!("select \"{}\"", t),
+            None => "select".to_string(),
+        },
+        graph::EdgeKind::ExCall => "excall".to_string(),
+    };
+    if edge.to.is_some() {
+        format!("{} -> {}", kind, target)
+    } else {
+        format!("{} -> {} (unresolved)", kind, target)
+    }
+}
+
+/// Render file to HTML page, returns the title of the page
+fn render_html(
+    f: &str,
+    name: &str,
+    output: &str,
+    voice: bool,
+    index: bool,
+    args: &args::Arg,
+) -> anyhow::Result<Option<String>> {
+    let ast = parse_file(f, args)?;
+    let opts = extract_options(f, &ast, args)?;
+    let messages = ast.get_messages(&opts)?;
+    print_warnings(f, &messages.warnings);
+    let title = messages
+        .savetitle
+        .as_ref()
+        .and_then(|t| t.first_key_value())
+        .map(|(_, v)| v.clone());
+    let w = utils::write_file(output)?;
+    let mut renderer = render::HtmlRenderer::new(std::io::BufWriter::new(w));
+    renderer.set_voice(voice);
+    renderer.set_tag_policy(opts.policy.clone());
+    if index {
+        renderer.set_index(Some("index.html"));
+    }
+    renderer.render(name, &messages)?;
+    Ok(title)
+}
+
+fn print_warnings(f: &str, warnings: &[String]) {
+    for w in warnings {
+        eprintln!("Warning: {}: {}", f, w);
+    }
+}
+
+fn to_json(
+    f: &str,
+    output: &str,
+    lang: Option<String>,
+    voice: bool,
+    line: bool,
+    args: &args::Arg,
+) -> anyhow::Result<bool> {
+    let ast = parse_file(f, args)?;
+    let opts = extract_options(f, &ast, args)?;
+    let (output_json, warnings) = ast.to_galtransl_json(lang, &opts, voice, line)?;
+    print_warnings(f, &warnings);
     if output_json.is_empty() {
         return Ok(false);
     }
@@ -45,65 +328,203 @@ fn to_json(f: &str, output: &str, lang: Option<String>) -> anyhow::Result<bool>
     Ok(true)
 }
 
+fn from_json(
+    f: &str,
+    json: &str,
+    output: &str,
+    lang: Option<String>,
+    target_lang: Option<String>,
+    source: Option<&str>,
+    args: &args::Arg,
+) -> anyhow::Result<()> {
+    let mut ast = parse_file(f, args)?;
+    let json = String::from_utf8(utils::read_file(json)?)?;
+    let source = match source {
+        Some(source) => Some(String::from_utf8(utils::read_file(source)?)?),
+        None => None,
+    };
+    let opts = extract_options(f, &ast, args)?;
+    let warnings = ast.import_galtransl_json(&json, lang, target_lang, source.as_deref(), &opts)?;
+    print_warnings(f, &warnings);
+    dump_file(&ast, output, args)
+}
+
+fn add_lang(
+    f: &str,
+    output: &str,
+    lang: &str,
+    target_lang: &str,
+    overwrite: bool,
+    args: &args::Arg,
+) -> anyhow::Result<usize> {
+    let mut ast = parse_file(f, args)?;
+    let count = ast.add_language(lang, target_lang, overwrite);
+    dump_file(&ast, output, args)?;
+    Ok(count)
+}
+
 fn main() {
     let args = args::Arg::parse();
     if args.backtrace {
         unsafe { std::env::set_var("RUST_LIB_BACKTRACE", "1") };
     }
+    if let Err(e) = tag_policy(&args) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
     match &args.command {
         args::Commands::TestParse { file } => {
-            let content = utils::read_file(file).unwrap();
-            let parser = parser::Parser::new(&content);
-            let ast = parser.parse().unwrap();
+            let (content, encoding) = read_source(file, &args).unwrap();
+            let (ast, diagnostics) = parse_recover(&content, encoding, &args).unwrap();
             println!("{:#?}", ast);
+            for d in diagnostics.iter() {
+                eprintln!("{}: {}", file, d);
+            }
+            if !diagnostics.is_empty() {
+                std::process::exit(1);
+            }
         }
-        args::Commands::Fmt { files, sort_blocks } => {
+        args::Commands::Fmt {
+            files,
+            sort_blocks,
+            check,
+        } => {
             let files = utils::collect_ast_files(files, args.recursive).unwrap();
             let mut error = 0;
+            let mut changed = 0;
             for f in files.iter() {
-                if let Err(e) = fmt_file(f, &args, *sort_blocks) {
-                    eprintln!("Error formatting file {}: {}", f, e);
-                    if args.backtrace {
-                        eprintln!("{}", e.backtrace());
+                match fmt_file(f, &args, *sort_blocks, *check) {
+                    Ok(true) => changed += 1,
+                    Ok(false) => {}
+                    Err(e) => {
+                        eprintln!("Error formatting file {}: {}", f, e);
+                        if args.backtrace {
+                            eprintln!("{}", e.backtrace());
+                        }
+                        error += 1;
                     }
-                    error += 1;
                 }
             }
-            eprintln!("Formatted {} files", files.len() - error);
+            if *check {
+                if changed != 0 {
+                    eprintln!("{} files are not formatted", changed);
+                }
+            } else {
+                eprintln!("Formatted {} files", files.len() - error);
+            }
             if error != 0 {
                 eprintln!("Failed to format {} files", error);
+            }
+            if error != 0 || (*check && changed != 0) {
                 std::process::exit(1);
             }
         }
         args::Commands::Message { cmd } => match cmd {
             args::MessageCmds::Test { file } => {
-                let content = utils::read_file(file).unwrap();
-                let parser = parser::Parser::new(&content);
-                let ast = parser.parse().unwrap();
-                println!("{:#?}", ast.get_messages().unwrap());
+                let ast = parse_file(file, &args).unwrap();
+                let opts = extract_options(file, &ast, &args).unwrap();
+                println!("{:#?}", ast.get_messages(&opts).unwrap());
             }
             args::MessageCmds::Render {
                 file,
                 output,
                 r#type,
+                voice,
+                lang,
             } => match r#type {
                 args::RenderType::Markdown => {
-                    let content = utils::read_file(file).unwrap();
-                    let parser = parser::Parser::new(&content);
-                    let ast = parser.parse().unwrap();
+                    let ast = parse_file(file, &args).unwrap();
                     let f = utils::write_file(output.as_deref().unwrap_or("-")).unwrap();
                     let f = std::io::BufWriter::new(f);
-                    let renderer = render::MarkdownRenderer::new(f);
-                    renderer.render(&ast.get_messages().unwrap()).unwrap();
+                    let mut renderer = render::MarkdownRenderer::new(f);
+                    renderer.set_voice(*voice);
+                    renderer.set_languages(lang.clone());
+                    let opts = extract_options(file, &ast, &args).unwrap();
+                    let messages = ast.get_messages(&opts).unwrap();
+                    print_warnings(file, &messages.warnings);
+                    renderer.set_tag_policy(opts.policy);
+                    renderer.render(&messages).unwrap();
+                }
+                args::RenderType::Html if std::path::Path::new(file).is_dir() => {
+                    let output = match output {
+                        Some(o) => o,
+                        None => {
+                            eprintln!("Output directory is required to render a directory");
+                            std::process::exit(1);
+                        }
+                    };
+                    std::fs::create_dir_all(output).unwrap();
+                    let files =
+                        utils::collect_ast_files(&vec![file.clone()], args.recursive).unwrap();
+                    let mut error = 0;
+                    let mut pages = Vec::new();
+                    for f in files.iter() {
+                        let name = std::path::Path::new(f)
+                            .file_stem()
+                            .map_or_else(|| f.clone(), |n| n.to_string_lossy().to_string());
+                        let output_file = std::path::PathBuf::from(output)
+                            .join(format!("{}.html", name))
+                            .to_string_lossy()
+                            .to_string();
+                        match render_html(f, &name, &output_file, *voice, true, &args) {
+                            Ok(title) => pages.push((name, title)),
+                            Err(e) => {
+                                eprintln!("Error rendering file {}: {}", f, e);
+                                if args.backtrace {
+                                    eprintln!("{}", e.backtrace());
+                                }
+                                error += 1;
+                            }
+                        }
+                    }
+                    pages.sort();
+                    let index = std::path::PathBuf::from(output).join("index.html");
+                    let f = utils::write_file(&index).unwrap();
+                    render::render_html_index(std::io::BufWriter::new(f), file, &pages).unwrap();
+                    eprintln!("Rendered {} files", files.len() - error);
+                    if error != 0 {
+                        eprintln!("Failed to render {} files", error);
+                        std::process::exit(1);
+                    }
+                }
+                args::RenderType::Html => {
+                    let name = std::path::Path::new(file)
+                        .file_stem()
+                        .map_or_else(|| file.clone(), |n| n.to_string_lossy().to_string());
+                    let output = output.as_deref().unwrap_or("-");
+                    render_html(file, &name, output, *voice, false, &args).unwrap();
+                }
+                args::RenderType::Dot | args::RenderType::Mermaid => {
+                    let ast = parse_file(file, &args).unwrap();
+                    let name = std::path::Path::new(file)
+                        .file_stem()
+                        .map_or_else(|| file.clone(), |n| n.to_string_lossy().to_string());
+                    let graph = graph::ScenarioGraph::new([(name.as_str(), &ast)]);
+                    let f = utils::write_file(output.as_deref().unwrap_or("-")).unwrap();
+                    let f = std::io::BufWriter::new(f);
+                    let format = match r#type {
+                        args::RenderType::Dot => render::FlowFormat::Dot,
+                        _ => render::FlowFormat::Mermaid,
+                    };
+                    let renderer = render::FlowRenderer::new(f, format);
+                    renderer.render(&graph, &name).unwrap();
                 }
             },
-            args::MessageCmds::ToJson { file, output, lang } => {
+            args::MessageCmds::ToJson {
+                file,
+                output,
+                lang,
+                voice,
+                line,
+            } => {
                 let files = utils::collect_ast_files(file, args.recursive).unwrap();
                 if files.len() == 1 {
-                    let content = utils::read_file(&files[0]).unwrap();
-                    let parser = parser::Parser::new(&content);
-                    let ast = parser.parse().unwrap();
-                    let output_json = ast.to_galtransl_json(lang.clone()).unwrap();
+                    let ast = parse_file(&files[0], &args).unwrap();
+                    let opts = extract_options(&files[0], &ast, &args).unwrap();
+                    let (output_json, warnings) = ast
+                        .to_galtransl_json(lang.clone(), &opts, *voice, *line)
+                        .unwrap();
+                    print_warnings(&files[0], &warnings);
                     if output_json.is_empty() {
                         eprintln!("Skipped empty file {}", files[0]);
                         std::process::exit(0);
@@ -126,7 +547,7 @@ fn main() {
                         let mut output_file = std::path::PathBuf::from(output).join(basename);
                         output_file.set_extension("json");
                         let output_file = output_file.to_string_lossy().to_string();
-                        match to_json(f, &output_file, lang.clone()) {
+                        match to_json(f, &output_file, lang.clone(), *voice, *line, &args) {
                             Ok(s) => {
                                 if !s {
                                     skiped += 1;
@@ -151,6 +572,291 @@ fn main() {
                     }
                 }
             }
+            args::MessageCmds::FromJson {
+                file,
+                json,
+                output,
+                lang,
+                target_lang,
+                source,
+            } => {
+                let files = utils::collect_ast_files(file, args.recursive).unwrap();
+                if files.len() == 1 {
+                    let output = output.as_deref().unwrap_or(&files[0]);
+                    from_json(
+                        &files[0],
+                        json,
+                        output,
+                        lang.clone(),
+                        target_lang.clone(),
+                        source.as_deref(),
+                        &args,
+                    )
+                    .unwrap();
+                } else {
+                    let mut error = 0;
+                    let mut skiped = 0;
+                    for f in files.iter() {
+                        let basename = match std::path::Path::new(f).file_name() {
+                            Some(b) => b.to_string_lossy().to_string(),
+                            None => {
+                                eprintln!("Error: {} is not a valid file", f);
+                                error += 1;
+                                continue;
+                            }
+                        };
+                        let mut json_file = std::path::PathBuf::from(json).join(&basename);
+                        json_file.set_extension("json");
+                        if !json_file.is_file() {
+                            skiped += 1;
+                            continue;
+                        }
+                        let source_file = source.as_ref().map(|s| {
+                            let mut p = std::path::PathBuf::from(s).join(&basename);
+                            p.set_extension("json");
+                            p.to_string_lossy().to_string()
+                        });
+                        let output_file = match output {
+                            Some(o) => std::path::PathBuf::from(o)
+                                .join(&basename)
+                                .to_string_lossy()
+                                .to_string(),
+                            None => f.clone(),
+                        };
+                        if let Err(e) = from_json(
+                            f,
+                            &json_file.to_string_lossy(),
+                            &output_file,
+                            lang.clone(),
+                            target_lang.clone(),
+                            source_file.as_deref(),
+                            &args,
+                        ) {
+                            eprintln!("Error importing file {}: {}", f, e);
+                            if args.backtrace {
+                                eprintln!("{}", e.backtrace());
+                            }
+                            error += 1;
+                        }
+                    }
+                    eprintln!("Imported {} files", files.len() - error - skiped);
+                    if skiped != 0 {
+                        eprintln!("Skipped {} files without JSON", skiped);
+                    }
+                    if error != 0 {
+                        eprintln!("Failed to import {} files", error);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            args::MessageCmds::AddLang {
+                file,
+                lang,
+                target_lang,
+                output,
+                overwrite,
+            } => {
+                let files = utils::collect_ast_files(file, args.recursive).unwrap();
+                let mut error = 0;
+                for f in files.iter() {
+                    let output_file = match output {
+                        Some(o) if files.len() == 1 => o.clone(),
+                        Some(o) => match std::path::Path::new(f).file_name() {
+                            Some(b) => std::path::PathBuf::from(o)
+                                .join(b)
+                                .to_string_lossy()
+                                .to_string(),
+                            None => {
+                                eprintln!("Error: {} is not a valid file", f);
+                                error += 1;
+                                continue;
+                            }
+                        },
+                        None => f.clone(),
+                    };
+                    match add_lang(f, &output_file, lang, target_lang, *overwrite, &args) {
+                        Ok(count) => {
+                            if count == 0 {
+                                eprintln!("Nothing added to file {}", f);
+                            }
+                        }
+                        Err(e) => {
+                            eprintln!("Error processing file {}: {}", f, e);
+                            if args.backtrace {
+                                eprintln!("{}", e.backtrace());
+                            }
+                            error += 1;
+                        }
+                    }
+                }
+                eprintln!("Processed {} files", files.len() - error);
+                if error != 0 {
+                    eprintln!("Failed to process {} files", error);
+                    std::process::exit(1);
+                }
+            }
         },
+        args::Commands::Graph { cmd } => match cmd {
+            args::GraphCmds::Test { files } => {
+                let (graph, failed) = or_exit(load_graph(files, &args));
+                println!("{:#?}", graph);
+                exit_if_failed(failed);
+            }
+            args::GraphCmds::Reachable { files, from } => {
+                let (graph, failed) = or_exit(load_graph(files, &args));
+                let starts: Vec<graph::NodeId> = if from.is_empty() {
+                    graph
+                        .files()
+                        .into_iter()
+                        .filter_map(|f| graph.top(f))
+                        .collect()
+                } else {
+                    from.iter().map(|f| f.parse().unwrap()).collect()
+                };
+                for id in graph.reachable(starts.iter()) {
+                    println!("{}", id);
+                }
+                exit_if_failed(failed);
+            }
+            args::GraphCmds::Path { files, to } => {
+                let (graph, failed) = or_exit(load_graph(files, &args));
+                let id: graph::NodeId = to.parse().unwrap();
+                match graph.nodes().get(&id) {
+                    Some(node) => {
+                        if let Some(line) = node.line {
+                            println!("Line: {}", line);
+                        }
+                        if !node.labels.is_empty() {
+                            println!("Labels: {}", node.labels.join(", "));
+                        }
+                    }
+                    None => {
+                        eprintln!("Block {} not found", id);
+                        std::process::exit(1);
+                    }
+                }
+                match graph.path_from_top(&id) {
+                    Some(path) => {
+                        println!("Path from top:");
+                        for (i, n) in path.iter().enumerate() {
+                            if i == 0 {
+                                println!("  {}", n);
+                            } else {
+                                let edge = graph
+                                    .successors(&path[i - 1])
+                                    .into_iter()
+                                    .find(|e| e.to.as_ref() == Some(n));
+                                match edge {
+                                    Some(edge) => println!("  {} ({})", n, describe_edge(edge)),
+                                    None => println!("  {}", n),
+                                }
+                            }
+                        }
+                    }
+                    None => println!("Not reachable from top of {}", id.file),
+                }
+                println!("Predecessors:");
+                for edge in graph.predecessors(&id) {
+                    println!("  {} {}", edge.from, describe_edge(edge));
+                }
+                exit_if_failed(failed);
+            }
+        },
+        args::Commands::Ast { cmd } => match cmd {
+            args::AstCmds::Diff {
+                old,
+                new,
+                text_only,
+            } => {
+                let old = parse_file(old, &args).unwrap();
+                let new = parse_file(new, &args).unwrap();
+                if old == new {
+                    return;
+                }
+                let changes = diff::diff(&old, &new, *text_only);
+                for change in changes.iter() {
+                    println!("{}", change);
+                }
+                if !changes.is_empty() {
+                    std::process::exit(1);
+                }
+            }
+            args::AstCmds::Merge {
+                base,
+                ours,
+                theirs,
+                output,
+            } => {
+                let parse = |f: &String| match parse_file(f, &args) {
+                    Ok(ast) => ast,
+                    Err(e) => {
+                        eprintln!("Error parsing file {}: {}", f, e);
+                        std::process::exit(1);
+                    }
+                };
+                let (base_ast, ours_ast, theirs_ast) = (parse(base), parse(ours), parse(theirs));
+                let (merged, conflicts, warnings) = merge::merge(&base_ast, &ours_ast, &theirs_ast);
+                print_warnings(ours, &warnings);
+                for c in conflicts.iter() {
+                    eprintln!("Conflict: {}", c);
+                }
+                if !conflicts.is_empty() && output.is_none() {
+                    eprintln!(
+                        "{} conflicts, {} is not written, use -o to write the merged file with conflict markers",
+                        conflicts.len(),
+                        ours
+                    );
+                    std::process::exit(1);
+                }
+                let output = output.as_deref().unwrap_or(ours);
+                if let Err(e) = dump_file(&merged, output, &args) {
+                    eprintln!("Error writing file {}: {}", output, e);
+                    std::process::exit(1);
+                }
+                if !conflicts.is_empty() {
+                    eprintln!("{} conflicts", conflicts.len());
+                    std::process::exit(1);
+                }
+            }
+        },
+        args::Commands::Verify { files } => {
+            let files = utils::collect_ast_files(files, args.recursive).unwrap();
+            let mut failed = 0;
+            for f in files.iter() {
+                match verify_file(f, &args) {
+                    Ok(divergences) => {
+                        for d in divergences.iter() {
+                            println!("{}: {}", f, d);
+                        }
+                        if !divergences.is_empty() {
+                            failed += 1;
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("Error verifying file {}: {}", f, e);
+                        failed += 1;
+                    }
+                }
+            }
+            eprintln!("Verified {} files", files.len() - failed);
+            if failed != 0 {
+                eprintln!("Failed to round trip {} files", failed);
+                std::process::exit(1);
+            }
+        }
+        args::Commands::Lint { files } => {
+            let (asts, failed) = or_exit(load_asts(files, &args));
+            let issues = lint::lint(&asts);
+            for (issue, span) in issues.iter() {
+                println!("{}{}", issue, types::span_suffix(*span));
+            }
+            if !issues.is_empty() {
+                eprintln!("Found {} issues in {} files", issues.len(), asts.len());
+            }
+            exit_if_failed(failed);
+            if !issues.is_empty() {
+                std::process::exit(1);
+            }
+        }
     }
 }
@@ -0,0 +1,550 @@
+use crate::diff::{block_names, value_text};
+use crate::types::{AstFile, Comments, KeyKind, Value};
+use crate::verify::{key_path, member_paths};
+use std::collections::BTreeMap;
+
+/// A block member which contains texts by language
+#[derive(Clone, Debug, PartialEq)]
+enum Container {
+    /// `text` or the text block of selects, like `select_1 = {ja = {...}}`
+    Key(String),
+    /// `{"savetitle", text = "...", ja = "..."}`
+    SaveTitle,
+}
+
+impl Container {
+    fn path(&self) -> String {
+        match self {
+            Container::Key(k) => key_path(k, KeyKind::for_key(k)),
+            Container::SaveTitle => ".savetitle".to_string(),
+        }
+    }
+
+    /// Index of the container in block
+    fn index(&self, block: &Value) -> Option<usize> {
+        let arr = match block {
+            Value::Array(arr) => arr,
+            _ => return None,
+        };
+        match self {
+            Container::Key(k) => block.keyval_index(k),
+            Container::SaveTitle => arr.iter().position(|v| {
+                v.get_member(0)
+                    .and_then(|v| v.as_str())
+                    .is_some_and(|s| s == "savetitle")
+            }),
+        }
+    }
+
+    fn get<'a>(&self, block: &'a Value) -> Option<&'a Value> {
+        self.index(block).and_then(|i| match block.get_member(i)? {
+            Value::KeyVal((_, v, _)) => Some(v.as_ref()),
+            v => Some(v),
+        })
+    }
+
+    fn get_mut<'a>(&self, block: &'a mut Value) -> Option<&'a mut Value> {
+        let i = self.index(block)?;
+        match block.get_member_mut(i)? {
+            Value::KeyVal((_, v, _)) => Some(v.as_mut()),
+            v => Some(v),
+        }
+    }
+}
+
+/// Containers of texts in block
+fn containers(block: &Value) -> Vec<Container> {
+    let mut result = Vec::new();
+    if block.find_keyval("text").is_some() {
+        result.push(Container::Key("text".to_string()));
+    }
+    for select in block.find_array_attrs("select") {
+        if let Some(text) = select.find_keyval("text").and_then(|v| v.as_str()) {
+            let c = Container::Key(text.to_string());
+            if block.find_keyval(text).is_some() && !result.contains(&c) {
+                result.push(c);
+            }
+        }
+    }
+    if !block.find_array_attrs("savetitle").is_empty() {
+        result.push(Container::SaveTitle);
+    }
+    result
+}
+
+/// Language keys in container, voices are not a language
+fn languages(container: &Value) -> Vec<String> {
+    match container {
+        Value::Array(arr) => arr
+            .iter()
+            .filter_map(|v| match v {
+                Value::KeyVal((k, _, _)) if k != "vo" => Some(k.clone()),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn remove_keyval(v: &mut Value, key: &str) {
+    if let Value::Array(arr) = v {
+        arr.retain(|v| !matches!(v, Value::KeyVal((k, _, _)) if k == key));
+    }
+}
+
+/// Block without the languages of its containers, used to compare other attributes
+fn strip_texts(block: &Value) -> Value {
+    let mut block = block.clone();
+    for c in containers(&block) {
+        if let Some(container) = c.get_mut(&mut block) {
+            for lang in languages(container) {
+                remove_keyval(container, &lang);
+            }
+        }
+    }
+    block
+}
+
+/// Both sides changed the same language of a block. The value of ours is kept.
+#[derive(Clone, Debug)]
+pub struct Conflict {
+    /// Path of the language, like `ast.block_00012.text.cn`
+    pub path: String,
+    block: String,
+    container: Container,
+    lang: String,
+    /// Value of theirs, None if it is removed
+    theirs: Option<Value>,
+}
+
+impl std::fmt::Display for Conflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: changed in both ours and theirs", self.path)
+    }
+}
+
+impl Conflict {
+    /// Position of the language in merged `ast` (see [Comments::values]),
+    /// like `[block, container, language]`
+    fn position(&self, ast: &Value) -> Option<Vec<usize>> {
+        let bi = match ast {
+            Value::Array(arr) => arr
+                .iter()
+                .position(|v| matches!(v, Value::KeyVal((k, _, _)) if *k == self.block))?,
+            _ => return None,
+        };
+        let block = ast.get_member(bi)?.find_keyval(&self.block)?;
+        let ci = self.container.index(block)?;
+        let li = self.container.get(block)?.keyval_index(&self.lang)?;
+        Some(vec![bi, ci, li])
+    }
+}
+
+/// Copies comments and number spellings of ours to the positions (see
+/// [Comments::values]) of the same members in merged file. Blocks are paired
+/// by name, other members by key or by index among members without key.
+struct Rekey<'a> {
+    ours: &'a AstFile,
+    comments: Comments,
+    numbers: BTreeMap<Vec<usize>, String>,
+}
+
+impl Rekey<'_> {
+    fn copy_at(&mut self, from: &[usize], to: &[usize]) {
+        if let Some(c) = self.ours.comments.get(from) {
+            self.comments.values.insert(to.to_vec(), c.clone());
+        }
+        if let Some(n) = self.ours.numbers.get(from) {
+            self.numbers.insert(to.to_vec(), n.clone());
+        }
+    }
+
+    /// Copy the end position of table, which has the comments before its `}`
+    fn copy_end(&mut self, from: &mut Vec<usize>, to: &mut Vec<usize>, a: usize, b: usize) {
+        from.push(a);
+        to.push(b);
+        self.copy_at(from, to);
+        from.pop();
+        to.pop();
+    }
+
+    fn root(&mut self, merged: &Value) {
+        let ours = self.ours;
+        self.copy_at(&[], &[]);
+        self.comments.trailing = ours.comments.trailing.clone();
+        self.comments.dropped = ours.comments.dropped;
+        let (a, b) = match (&ours.ast, merged) {
+            (Value::Array(a), Value::Array(b)) => (a, b),
+            _ => return,
+        };
+        for (j, v) in b.iter().enumerate() {
+            if let Value::KeyVal((name, _, _)) = v
+                && let Some(i) = ours.block_index(name)
+            {
+                self.copy(&mut vec![i], &mut vec![j], &a[i], v);
+            }
+        }
+        self.copy_end(&mut Vec::new(), &mut Vec::new(), a.len(), b.len());
+    }
+
+    fn copy(&mut self, from: &mut Vec<usize>, to: &mut Vec<usize>, ours: &Value, merged: &Value) {
+        self.copy_at(from, to);
+        let (a, b) = match (keyval_value(ours), keyval_value(merged)) {
+            (Value::Array(a), Value::Array(b)) => (a, b),
+            _ => return,
+        };
+        let paths = member_paths(b);
+        let mut used = vec![false; b.len()];
+        for (i, (p, v)) in member_paths(a).iter().zip(a).enumerate() {
+            if let Some(j) = (0..b.len()).find(|j| !used[*j] && paths[*j] == *p) {
+                used[j] = true;
+                from.push(i);
+                to.push(j);
+                self.copy(from, to, v, &b[j]);
+                from.pop();
+                to.pop();
+            }
+        }
+        self.copy_end(from, to, a.len(), b.len());
+    }
+}
+
+/// Value of keyval, a keyval and its value share the same position
+fn keyval_value(v: &Value) -> &Value {
+    match v {
+        Value::KeyVal((_, v, _)) => v,
+        v => v,
+    }
+}
+
+struct Merger {
+    conflicts: Vec<Conflict>,
+    warnings: Vec<String>,
+}
+
+impl Merger {
+    fn merge_block(
+        &mut self,
+        name: &str,
+        base: Option<&Value>,
+        ours: &Value,
+        theirs: &Value,
+    ) -> Value {
+        let path = format!("ast{}", key_path(name, KeyKind::for_key(name)));
+        let base_attrs = base.map(strip_texts);
+        let ours_attrs = strip_texts(ours);
+        let theirs_attrs = strip_texts(theirs);
+        let ours_changed = base_attrs.as_ref() != Some(&ours_attrs);
+        let theirs_changed = base_attrs.as_ref() != Some(&theirs_attrs);
+        let mut result = if ours_changed && !theirs_changed {
+            ours.clone()
+        } else {
+            if ours_changed && ours_attrs != theirs_attrs {
+                self.warnings.push(format!(
+                    "{}: attributes changed in both ours and theirs, theirs is used",
+                    path
+                ));
+            }
+            theirs.clone()
+        };
+        let mut all = containers(ours);
+        for c in containers(theirs) {
+            if !all.contains(&c) {
+                all.push(c);
+            }
+        }
+        for c in all {
+            let cpath = format!("{}{}", path, c.path());
+            let b = base.and_then(|b| c.get(b));
+            let o = c.get(ours);
+            let t = c.get(theirs);
+            let mut langs = Vec::new();
+            for v in [o, t, b].into_iter().flatten() {
+                for lang in languages(v) {
+                    if !langs.contains(&lang) {
+                        langs.push(lang);
+                    }
+                }
+            }
+            let mut upstream = Vec::new();
+            let mut translated = Vec::new();
+            let mut merged = Vec::new();
+            for lang in langs {
+                let bv = b.and_then(|v| v.find_keyval(&lang));
+                let ov = o.and_then(|v| v.find_keyval(&lang));
+                let tv = t.and_then(|v| v.find_keyval(&lang));
+                if tv != bv && bv.is_some() {
+                    upstream.push(lang.clone());
+                }
+                if ov != bv {
+                    translated.push(lang.clone());
+                }
+                let value = if ov == bv || ov == tv {
+                    tv
+                } else if tv == bv {
+                    ov
+                } else {
+                    self.conflicts.push(Conflict {
+                        path: format!("{}{}", cpath, key_path(&lang, KeyKind::for_key(&lang))),
+                        block: name.to_string(),
+                        container: c.clone(),
+                        lang: lang.clone(),
+                        theirs: tv.cloned(),
+                    });
+                    ov
+                };
+                merged.push((lang, value.cloned()));
+            }
+            let container = match c.get_mut(&mut result) {
+                Some(container) => container,
+                None => {
+                    if !translated.is_empty() {
+                        self.warnings.push(format!(
+                            "{}: removed in theirs, changes of ours are dropped",
+                            cpath
+                        ));
+                    }
+                    continue;
+                }
+            };
+            for (lang, value) in merged {
+                match value {
+                    Some(v) => container.set_keyval(&lang, v),
+                    None => remove_keyval(container, &lang),
+                }
+            }
+            let stale: Vec<&String> = translated
+                .iter()
+                .filter(|l| !upstream.contains(l))
+                .collect();
+            if !upstream.is_empty() && !stale.is_empty() {
+                self.warnings.push(format!(
+                    "{}: {} changed in theirs, {} of ours may be outdated",
+                    cpath,
+                    upstream.join(", "),
+                    stale
+                        .iter()
+                        .map(|s| s.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ));
+            }
+        }
+        result
+    }
+}
+
+/// Three-way merge of blocks. Languages in text blocks are merged one by one,
+/// other attributes of a block are taken from the side which changed them,
+/// theirs wins if both changed. Blocks are in the order of theirs, followed
+/// by the blocks only added in ours.
+///
+/// Returns the merged file, conflicts and warnings. The value of ours is kept
+/// for conflicts and marked with comments in the merged file.
+pub fn merge(
+    base: &AstFile,
+    ours: &AstFile,
+    theirs: &AstFile,
+) -> (AstFile, Vec<Conflict>, Vec<String>) {
+    let mut merger = Merger {
+        conflicts: Vec::new(),
+        warnings: Vec::new(),
+    };
+    let base_blocks = base.get_blocks();
+    let ours_blocks = ours.get_blocks();
+    let theirs_blocks = theirs.get_blocks();
+    let mut names = block_names(theirs);
+    for name in block_names(ours) {
+        if !theirs_blocks.contains_key(&name) && !base_blocks.contains_key(&name) {
+            names.push(name);
+        }
+    }
+    let mut blocks = Vec::new();
+    for name in block_names(base) {
+        if theirs_blocks.contains_key(&name) == ours_blocks.contains_key(&name) {
+            continue;
+        }
+        let b = &base_blocks[&name];
+        let path = format!("ast{}", key_path(&name, KeyKind::for_key(&name)));
+        match (ours_blocks.get(&name), theirs_blocks.get(&name)) {
+            (Some(o), None) if o != b => merger
+                .warnings
+                .push(format!("{}: removed in theirs but changed in ours", path)),
+            (None, Some(t)) if t != b => {
+                merger
+                    .warnings
+                    .push(format!("{}: removed in ours but changed in theirs", path));
+            }
+            _ => {}
+        }
+    }
+    for name in names {
+        let b = base_blocks.get(&name).map(|v| v.as_ref());
+        let block = match (ours_blocks.get(&name), theirs_blocks.get(&name)) {
+            (Some(o), Some(t)) => merger.merge_block(&name, b, o, t),
+            (None, Some(t)) => {
+                // Removed in ours, keep it only if theirs changed it
+                if b.is_some_and(|b| b == t.as_ref()) {
+                    continue;
+                }
+                t.as_ref().clone()
+            }
+            (Some(o), None) => o.as_ref().clone(),
+            (None, None) => continue,
+        };
+        blocks.push(Value::key_val(name, block));
+    }
+    let ast = Value::Array(blocks);
+    let mut rekey = Rekey {
+        ours,
+        comments: Comments::default(),
+        numbers: BTreeMap::new(),
+    };
+    rekey.root(&ast);
+    let Rekey {
+        mut comments,
+        numbers,
+        ..
+    } = rekey;
+    for c in merger.conflicts.iter() {
+        if let Some(pos) = c.position(&ast) {
+            let theirs = match &c.theirs {
+                Some(v) => format!("-- theirs: {} = {}", c.lang, value_text(v)),
+                None => format!("-- theirs: {} is removed", c.lang),
+            };
+            // After the comments of ours, so markers are next to the value
+            comments.values.entry(pos).or_default().extend([
+                "-- <<<<<<< conflict, ours is kept".to_string(),
+                theirs,
+                "-- >>>>>>>".to_string(),
+            ]);
+        }
+    }
+    let ast = AstFile {
+        astver: theirs.astver,
+        astname: theirs.astname.clone(),
+        ast,
+        comments,
+        numbers,
+        spans: BTreeMap::new(),
+        encoding: ours.encoding,
+        line_ending: ours.line_ending,
+        bom: ours.bom,
+        final_newline: ours.final_newline,
+    };
+    (ast, merger.conflicts, merger.warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::verify::position_path;
+
+    fn parse(src: &str) -> AstFile {
+        let mut parser = Parser::new(&src);
+        parser.set_keep_comments(true);
+        parser.parse().unwrap()
+    }
+
+    const BASE: &str = r#"astver = 2.0
+ast = {
+    block_00000 = {
+        {"bg", file = "bg01"},
+        text = {ja = {{"あ"}}},
+        line = 1,
+    },
+    block_00001 = {
+        {"select", text = "select_1"},
+        select_1 = {ja = {"はい"}},
+        line = 2,
+    },
+}
+"#;
+
+    #[test]
+    fn conflict_markers_at_language() {
+        let base = parse(BASE);
+        let ours = parse(&BASE.replace(r#"ja = {"はい"}"#, r#"ja = {"はい"}, cn = {"是"}"#));
+        let theirs = parse(
+            &BASE
+                .replace(r#"ja = {"はい"}"#, r#"ja = {"はい"}, cn = {"对"}"#)
+                .replace(
+                    "    block_00001",
+                    "    block_new = {line = 3},\n    block_00001",
+                ),
+        );
+        let (merged, conflicts, _) = merge(&base, &ours, &theirs);
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].path, "ast.block_00001.select_1.cn");
+        // block_00001 is after the new block of theirs, cn is after ja
+        let pos = vec![2, 1, 1];
+        assert_eq!(conflicts[0].position(&merged.ast), Some(pos.clone()));
+        assert_eq!(position_path(&merged.ast, &pos), conflicts[0].path);
+        assert_eq!(
+            merged.comments.get(&pos),
+            Some(&vec![
+                "-- <<<<<<< conflict, ours is kept".to_string(),
+                r#"-- theirs: cn = {"对"}"#.to_string(),
+                "-- >>>>>>>".to_string(),
+            ])
+        );
+        assert_eq!(
+            merged
+                .ast
+                .find_keyval("block_00001")
+                .unwrap()
+                .find_keyval("select_1"),
+            ours.ast
+                .find_keyval("block_00001")
+                .unwrap()
+                .find_keyval("select_1"),
+        );
+    }
+
+    #[test]
+    fn comments_of_ours_are_kept() {
+        let base = parse(BASE);
+        let ours = parse(
+            &BASE
+                .replace("    block_00001", "    -- note\n    block_00001")
+                .replace(
+                    r#"{{"あ"}}},"#,
+                    r#"{{"あ"}}, -- checked
+            cn = {{"a"}}},"#,
+                )
+                .replace("line = 2", "line = 0x2"),
+        );
+        let theirs = parse(
+            &BASE
+                .replace(r#"file = "bg01""#, r#"file = "bg02""#)
+                .replace(
+                    "    block_00000",
+                    "    block_new = {line = 0},\n    block_00000",
+                ),
+        );
+        let (merged, conflicts, _) = merge(&base, &ours, &theirs);
+        assert!(conflicts.is_empty());
+        let comments: Vec<_> = merged
+            .comments
+            .values
+            .iter()
+            .map(|(pos, c)| (position_path(&merged.ast, pos), c.clone()))
+            .collect();
+        assert_eq!(
+            comments,
+            vec![
+                (
+                    "ast.block_00000.text.cn".to_string(),
+                    vec!["-- checked".to_string()]
+                ),
+                ("ast.block_00001".to_string(), vec!["-- note".to_string()]),
+            ]
+        );
+        assert_eq!(merged.numbers.get(&vec![2, 2]), Some(&"0x2".to_string()));
+        assert_eq!(
+            merged.ast.find_keyval("block_00000").unwrap().get_member(0),
+            theirs.ast.find_keyval("block_00000").unwrap().get_member(0),
+        );
+    }
+}
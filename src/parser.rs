@@ -1,13 +1,66 @@
+use crate::dump::format_number;
 use crate::types::*;
+use std::collections::BTreeMap;
 
 pub type Result<T> = std::result::Result<T, anyhow::Error>;
 
+/// A parse error with its location in source
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub line: usize,
+    /// Column in characters, starts from 1
+    pub column: usize,
+    pub byte: usize,
+    pub message: String,
+    /// What the parser expected, like `'='`
+    pub expected: Option<String>,
+    /// The token at the position, like `'x'` or `end of file`
+    pub found: String,
+    /// The source line which contains the position
+    pub snippet: String,
+    /// Offset of the position in the snippet in characters
+    pub caret: usize,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Failed to parse at position line {} column {} (byte {}): {}",
+            self.line, self.column, self.byte, self.message
+        )?;
+        if self.expected.is_some() {
+            write!(f, ", found {}", self.found)?;
+        }
+        let indent: String = self
+            .snippet
+            .chars()
+            .take(self.caret)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        write!(f, "\n    {}\n    {}^", self.snippet, indent)
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
 pub struct Parser<'a> {
     str: &'a [u8],
     pos: usize,
     len: usize,
     line: usize,
+    /// Column of the current position in characters, starts from 1
     line_index: usize,
+    keep_comments: bool,
+    comments: Comments,
+    numbers: BTreeMap<Vec<usize>, String>,
+    pending_comments: Vec<String>,
+    /// Position of the current array, see [Comments::values]
+    array_pos: Vec<usize>,
+    recover: bool,
+    diagnostics: Vec<Diagnostic>,
+    keep_spans: bool,
+    spans: BTreeMap<Vec<usize>, Span>,
 }
 
 impl<'a> Parser<'a> {
@@ -19,10 +72,47 @@ impl<'a> Parser<'a> {
             len: str.len(),
             line: 1,
             line_index: 1,
+            keep_comments: false,
+            comments: Comments::default(),
+            numbers: BTreeMap::new(),
+            pending_comments: Vec::new(),
+            array_pos: Vec::new(),
+            recover: false,
+            diagnostics: Vec::new(),
+            keep_spans: false,
+            spans: BTreeMap::new(),
         }
     }
 
+    /// Keep comments in [AstFile::comments] so they can be written back by dumper
+    pub fn set_keep_comments(&mut self, keep: bool) {
+        self.keep_comments = keep;
+    }
+
+    /// Keep source spans of values in [AstFile::spans], so messages can point to source
+    pub fn set_keep_spans(&mut self, keep: bool) {
+        self.keep_spans = keep;
+    }
+
     pub fn parse(mut self) -> Result<AstFile> {
+        self.parse_file()
+    }
+
+    /// Parse with error recovery. A broken member of table is skipped to the
+    /// next `,` or `}` of the table and reported as a diagnostic, so the
+    /// returned file only contains the members which can be parsed.
+    /// Returns error if the header of file can not be parsed.
+    pub fn parse_recover(mut self) -> Result<(AstFile, Vec<Diagnostic>)> {
+        self.recover = true;
+        let ast = self.parse_file()?;
+        Ok((ast, self.diagnostics))
+    }
+
+    fn parse_file(&mut self) -> Result<AstFile> {
+        let bom = self.str.starts_with(b"\xEF\xBB\xBF");
+        if bom {
+            self.pos = 3;
+        }
         self.erase_whitespace();
         self.parse_indent(b"astver")?;
         self.parse_equal()?;
@@ -32,24 +122,40 @@ impl<'a> Parser<'a> {
         if self.is_indent(b"astname") {
             self.parse_indent(b"astname")?;
             self.parse_equal()?;
-            astname = Some(self.parse_str()?.to_string());
+            astname = Some(self.parse_str()?);
             self.erase_whitespace();
         }
         self.parse_indent(b"ast")?;
         self.parse_equal()?;
+        self.erase_whitespace();
+        self.attach_comments(Vec::new());
+        let start = (self.pos, self.line, self.line_index);
         let ast = self.parse_value()?;
+        self.add_span(Vec::new(), start);
+        self.erase_whitespace();
+        self.comments.trailing = std::mem::take(&mut self.pending_comments);
         Ok(AstFile {
             astver,
             astname,
             ast,
+            comments: std::mem::take(&mut self.comments),
+            numbers: std::mem::take(&mut self.numbers),
+            spans: std::mem::take(&mut self.spans),
+            encoding: encoding_rs::UTF_8,
+            line_ending: LineEnding::detect(self.str),
+            bom,
+            final_newline: self.str.ends_with(b"\n"),
         })
     }
 
     fn parse_equal(&mut self) -> Result<()> {
         self.erase_whitespace();
-        match self.next() {
-            Some(b'=') => Ok(()),
-            _ => self.error("expected '='"),
+        match self.peek() {
+            Some(b'=') => {
+                self.eat_char();
+                Ok(())
+            }
+            _ => self.expected("'='"),
         }
     }
 
@@ -57,13 +163,26 @@ impl<'a> Parser<'a> {
         self.erase_whitespace();
         match self.peek() {
             Some(t) => match t {
-                b'"' => return self.parse_str().map(|x| Value::Str(x.to_string())),
-                b'-' | b'.' | b'0'..=b'9' => return self.parse_any_number(),
-                b'_' | b'a'..=b'z' | b'A'..=b'Z' | b'[' | b']' => return self.parse_key_val(),
-                b'{' => return self.parse_array(),
-                _ => return self.error(format!("unexpected token: {}", t)),
+                b'"' | b'\'' => self.parse_str().map(Value::Str),
+                b'[' if self.long_bracket_level().is_some() => self.parse_str().map(Value::Str),
+                b'-' | b'+' | b'.' | b'0'..=b'9' => self.parse_any_number(),
+                b'_' | b'a'..=b'z' | b'A'..=b'Z' => match self.value_word() {
+                    Some(word @ (b"true" | b"false" | b"nil")) => self.parse_word_value(word),
+                    Some(word)
+                        if matches!(
+                            word.to_ascii_lowercase().as_slice(),
+                            b"inf" | b"infinity" | b"nan"
+                        ) =>
+                    {
+                        self.parse_word_value(word)
+                    }
+                    _ => self.parse_key_val(),
+                },
+                b'[' | 0x80.. => self.parse_key_val(),
+                b'{' => self.parse_array(),
+                _ => self.error("unexpected token"),
             },
-            None => return self.error("unexpected eof"),
+            None => self.error("unexpected eof"),
         }
     }
 
@@ -73,14 +192,36 @@ impl<'a> Parser<'a> {
         let mut array = Vec::new();
         loop {
             self.erase_whitespace();
+            let mut pos = self.array_pos.clone();
+            pos.push(array.len());
+            self.attach_comments(pos.clone());
             match self.peek() {
                 Some(b'}') => {
                     self.eat_char();
                     break;
                 }
                 Some(_) => {
-                    let val = self.parse_value()?;
-                    array.push(val);
+                    let start = (self.pos, self.line, self.line_index);
+                    let parent = std::mem::replace(&mut self.array_pos, pos);
+                    let val = self.parse_value();
+                    self.array_pos = parent;
+                    match val {
+                        Ok(val) => {
+                            let mut pos = self.array_pos.clone();
+                            pos.push(array.len());
+                            self.add_span(pos, start);
+                            array.push(val);
+                        }
+                        Err(e) if self.recover => {
+                            self.add_diagnostic(e);
+                            // Skip from the start of value, so an error inside
+                            // string does not make its closing quote an opening one
+                            (self.pos, self.line, self.line_index) = start;
+                            self.skip_to_separator();
+                        }
+                        Err(e) => return Err(e),
+                    }
+                    self.erase_whitespace();
                     match self.peek() {
                         Some(b',') => {
                             self.eat_char();
@@ -88,82 +229,438 @@ impl<'a> Parser<'a> {
                         _ => {}
                     }
                 }
+                None if self.recover => {
+                    self.add_diagnostic(self.expected::<_, ()>("'}'").unwrap_err());
+                    break;
+                }
                 None => return self.error("unexpected eof"),
             }
         }
         Ok(Value::Array(array))
     }
 
+    /// Record the span of value from `start` (byte, line, column) to current position
+    fn add_span(&mut self, pos: Vec<usize>, start: (usize, usize, usize)) {
+        if self.keep_spans {
+            let (start, line, column) = start;
+            let span = Span {
+                start,
+                end: self.pos,
+                line,
+                column,
+            };
+            self.spans.insert(pos, span);
+        }
+    }
+
+    /// Record error in recover mode, errors at the same position are only recorded once
+    fn add_diagnostic(&mut self, e: anyhow::Error) {
+        let d = match e.downcast::<Diagnostic>() {
+            Ok(d) => d,
+            Err(e) => self.diagnostic(e, None),
+        };
+        if self
+            .diagnostics
+            .last()
+            .is_none_or(|last| last.byte != d.byte)
+        {
+            self.diagnostics.push(d);
+        }
+    }
+
+    /// Skip to the next `,` or `}` of current table,
+    /// nested tables, strings and comments are skipped
+    fn skip_to_separator(&mut self) {
+        let mut depth = 0;
+        loop {
+            self.erase_whitespace();
+            match self.peek() {
+                None => break,
+                Some(b',' | b'}') if depth == 0 => break,
+                Some(b'{') => {
+                    depth += 1;
+                    self.next();
+                }
+                Some(b'}') => {
+                    depth -= 1;
+                    self.next();
+                }
+                Some(quote @ (b'"' | b'\'')) => {
+                    self.next();
+                    loop {
+                        match self.next() {
+                            Some(b'\\') => {
+                                self.next();
+                            }
+                            Some(b'\n') | None => break,
+                            Some(c) if c == quote => break,
+                            Some(_) => {}
+                        }
+                    }
+                }
+                Some(_) => match self.long_bracket_level() {
+                    Some(level) => {
+                        self.skip_long_bracket(level);
+                    }
+                    None => {
+                        self.next();
+                    }
+                },
+            }
+        }
+    }
+
+    /// Parse a Lua numeric literal, the original spelling is kept in [AstFile::numbers]
     fn parse_any_number(&mut self) -> Result<Value> {
+        let (s, v) = self.lex_number()?;
+        if s != format_number(&v) {
+            self.numbers.insert(self.array_pos.clone(), s.to_string());
+        }
+        Ok(v)
+    }
+
+    fn lex_number(&mut self) -> Result<(&'a str, Value)> {
         self.erase_whitespace();
         let start = self.pos;
+        let (line, column) = (self.line, self.line_index);
+        if let Some(b'-' | b'+') = self.peek() {
+            self.eat_char();
+        }
+        let body = self.pos;
+        let hex = self.is_indent(b"0x") || self.is_indent(b"0X");
+        if hex {
+            self.eat_char();
+            self.eat_char();
+        }
+        let exponent: &[u8] = if hex { b"pP" } else { b"eE" };
         while let Some(c) = self.peek() {
-            if c == b'.' || c == b'-' || c.is_ascii_digit() {
+            if exponent.contains(&c) {
+                self.eat_char();
+                if let Some(b'-' | b'+') = self.peek() {
+                    self.eat_char();
+                }
+            } else if c == b'.' || (hex && c.is_ascii_hexdigit()) || c.is_ascii_digit() {
                 self.eat_char();
             } else {
                 break;
             }
         }
-        let s = std::str::from_utf8(&self.str[start..self.pos])?;
-        if s.contains('.') {
-            s.parse()
-                .map(Value::Float)
-                .map_err(|e| self.error2(format!("failed to parse f64: {}", e)))
-        } else {
-            s.parse()
-                .map(Value::Int)
-                .map_err(|e| self.error2(format!("failed to parse i64: {}", e)))
+        if self.pos == body {
+            // inf or nan
+            while let Some(b'a'..=b'z' | b'A'..=b'Z') = self.peek() {
+                self.eat_char();
+            }
         }
-    }
-
-    fn parse_f64(&mut self) -> Result<f64> {
-        self.erase_whitespace();
-        let start = self.pos;
+        let mut malformed = false;
         while let Some(c) = self.peek() {
-            if c == b'.' || c == b'-' || c.is_ascii_digit() {
+            if c.is_ascii_alphanumeric() || matches!(c, b'_' | b'.' | b'-' | b'+') {
                 self.eat_char();
+                malformed = true;
             } else {
                 break;
             }
         }
-        let s = std::str::from_utf8(&self.str[start..self.pos])?;
-        s.parse()
-            .map_err(|e| self.error2(format!("failed to parse f64: {}", e)))
+        let s: &'a str = std::str::from_utf8(&self.str[start..self.pos])?;
+        match parse_number(s) {
+            Some(v) if !malformed => Ok((s, v)),
+            _ => {
+                // Point to the start of number
+                (self.pos, self.line, self.line_index) = (start, line, column);
+                self.error(format!("malformed number near '{}'", s))
+            }
+        }
+    }
+
+    /// Parse a number as float, the original spelling is kept in
+    /// [AstFile::numbers] if it is different from the float's, like `2`
+    fn parse_f64(&mut self) -> Result<f64> {
+        let (s, v) = self.lex_number()?;
+        let f = match v {
+            Value::Float(f) => f,
+            Value::Int(i) => i as f64,
+            _ => return self.error("expected number"),
+        };
+        if s != format_number(&Value::Float(f)) {
+            self.numbers.insert(self.array_pos.clone(), s.to_string());
+        }
+        Ok(f)
     }
 
-    fn parse_str(&mut self) -> Result<&'a str> {
+    /// Identifier at current position if it is a value like `true` instead of
+    /// the key of a keyval, which means it is not followed by `=`
+    fn value_word(&self) -> Option<&'a [u8]> {
+        let str: &'a [u8] = self.str;
+        let mut end = self.pos;
+        while end < self.len && (str[end].is_ascii_alphanumeric() || str[end] == b'_') {
+            end += 1;
+        }
+        let word = &str[self.pos..end];
+        while end < self.len && str[end].is_ascii_whitespace() {
+            end += 1;
+        }
+        if str.get(end) == Some(&b'=') {
+            None
+        } else {
+            Some(word)
+        }
+    }
+
+    /// Parse `true`, `false`, `nil`, or `inf` and `nan` as numbers
+    fn parse_word_value(&mut self, word: &[u8]) -> Result<Value> {
+        let v = match word {
+            b"true" => Value::Bool(true),
+            b"false" => Value::Bool(false),
+            b"nil" => Value::Nil,
+            _ => return self.parse_any_number(),
+        };
+        for _ in 0..word.len() {
+            self.eat_char();
+        }
+        Ok(v)
+    }
+
+    /// Parse a Lua string literal: double or single quoted with escape
+    /// sequences, or a long bracket string like `[[...]]` and `[==[...]==]`
+    fn parse_str(&mut self) -> Result<String> {
         self.erase_whitespace();
-        self.parse_indent(b"\"")?;
-        let start = self.pos;
-        let end = loop {
+        let quote = match self.peek() {
+            Some(b'[') => return self.parse_long_str(),
+            Some(c @ (b'"' | b'\'')) => c,
+            _ => return self.expected("string"),
+        };
+        self.eat_char();
+        let mut buf = Vec::new();
+        loop {
+            if let Some(b'\n' | b'\r') = self.peek() {
+                return self.error("unfinished string");
+            }
             match self.next() {
-                Some(c) => {
-                    if c == b'"' {
-                        break self.pos - 1;
+                Some(b'\\') => {
+                    // Errors of escape sequence point to its `\`
+                    let start = (self.pos - 1, self.line, self.line_index - 1);
+                    if let Err(msg) = self.parse_escape(&mut buf) {
+                        (self.pos, self.line, self.line_index) = start;
+                        return self.error(msg);
                     }
                 }
+                Some(c) if c == quote => break,
+                Some(c) => buf.push(c),
                 None => return self.error("unexpected eof"),
             }
+        }
+        String::from_utf8(buf).map_err(|e| self.error2(e))
+    }
+
+    /// Parse escape sequence after `\` and push the bytes to `buf`, returns the error message
+    fn parse_escape(&mut self, buf: &mut Vec<u8>) -> std::result::Result<(), String> {
+        match self.next() {
+            Some(b'n') => buf.push(b'\n'),
+            Some(b't') => buf.push(b'\t'),
+            Some(b'r') => buf.push(b'\r'),
+            Some(b'a') => buf.push(0x07),
+            Some(b'b') => buf.push(0x08),
+            Some(b'f') => buf.push(0x0c),
+            Some(b'v') => buf.push(0x0b),
+            Some(c @ (b'\\' | b'"' | b'\'')) => buf.push(c),
+            Some(b'\n') => {
+                buf.push(b'\n');
+                if self.peek() == Some(b'\r') {
+                    self.eat_char();
+                }
+            }
+            Some(b'\r') => {
+                buf.push(b'\n');
+                if self.peek() == Some(b'\n') {
+                    self.next();
+                }
+            }
+            Some(b'z') => {
+                while let Some(c) = self.peek() {
+                    if c.is_ascii_whitespace() {
+                        self.next();
+                    } else {
+                        break;
+                    }
+                }
+            }
+            Some(b'x') => {
+                let mut v = 0;
+                for _ in 0..2 {
+                    match self.next().and_then(|c| (c as char).to_digit(16)) {
+                        Some(d) => v = v * 16 + d,
+                        None => return Err("hexadecimal digit expected in \\x escape".to_string()),
+                    }
+                }
+                buf.push(v as u8);
+            }
+            Some(c @ b'0'..=b'9') => {
+                let mut v = (c - b'0') as u32;
+                for _ in 0..2 {
+                    match self.peek() {
+                        Some(c @ b'0'..=b'9') => {
+                            v = v * 10 + (c - b'0') as u32;
+                            self.eat_char();
+                        }
+                        _ => break,
+                    }
+                }
+                if v > 255 {
+                    return Err("decimal escape too large".to_string());
+                }
+                buf.push(v as u8);
+            }
+            Some(b'u') => {
+                if self.next() != Some(b'{') {
+                    return Err("missing '{' in \\u{xxxx}".to_string());
+                }
+                let mut v: u32 = 0;
+                let mut digits = 0;
+                loop {
+                    match self.next() {
+                        Some(b'}') if digits > 0 => break,
+                        Some(c) if c.is_ascii_hexdigit() => {
+                            v = v
+                                .checked_mul(16)
+                                .and_then(|v| v.checked_add((c as char).to_digit(16).unwrap()))
+                                .ok_or_else(|| "UTF-8 value too large".to_string())?;
+                            digits += 1;
+                        }
+                        _ => return Err("hexadecimal digit expected in \\u{xxxx}".to_string()),
+                    }
+                }
+                let c = char::from_u32(v)
+                    .ok_or_else(|| format!("invalid unicode code point {:X}", v))?;
+                let mut tmp = [0; 4];
+                buf.extend_from_slice(c.encode_utf8(&mut tmp).as_bytes());
+            }
+            Some(c) => return Err(format!("invalid escape sequence \\{}", c as char)),
+            None => return Err("unexpected eof".to_string()),
+        }
+        Ok(())
+    }
+
+    /// Level of long bracket at current position, like 2 for `[==[`
+    fn long_bracket_level(&self) -> Option<usize> {
+        if self.peek() != Some(b'[') {
+            return None;
+        }
+        let mut level = 0;
+        loop {
+            match self.str.get(self.pos + 1 + level) {
+                Some(b'=') => level += 1,
+                Some(b'[') => return Some(level),
+                _ => return None,
+            }
+        }
+    }
+
+    fn parse_long_str(&mut self) -> Result<String> {
+        let level = match self.long_bracket_level() {
+            Some(level) => level,
+            None => return self.error("invalid long string delimiter"),
+        };
+        let (start, end) = match self.skip_long_bracket(level) {
+            Some(range) => range,
+            None => return self.error("unfinished long string"),
         };
-        std::str::from_utf8(&self.str[start..end]).map_err(|e| self.error2(e))
+        std::str::from_utf8(&self.str[start..end])
+            .map(|s| s.to_string())
+            .map_err(|e| self.error2(e))
+    }
+
+    /// Skip a long bracket of `level` at current position and return the range of its content.
+    /// Returns None if the closing bracket is not found.
+    fn skip_long_bracket(&mut self, level: usize) -> Option<(usize, usize)> {
+        for _ in 0..level + 2 {
+            self.next();
+        }
+        // A newline immediately following the opening bracket is skipped
+        match self.peek() {
+            Some(b'\r') => {
+                self.next();
+                if self.peek() == Some(b'\n') {
+                    self.next();
+                }
+            }
+            Some(b'\n') => {
+                self.next();
+                if self.peek() == Some(b'\r') {
+                    self.next();
+                }
+            }
+            _ => {}
+        }
+        let start = self.pos;
+        loop {
+            match self.next() {
+                Some(b']') => {
+                    let close = &self.str[self.pos..];
+                    if close.len() > level
+                        && close[..level].iter().all(|c| *c == b'=')
+                        && close[level] == b']'
+                    {
+                        let end = self.pos - 1;
+                        for _ in 0..level + 1 {
+                            self.next();
+                        }
+                        return Some((start, end));
+                    }
+                }
+                Some(_) => {}
+                None => return None,
+            }
+        }
     }
 
+    /// Skip whitespaces and comments. Comments are kept in pending comments
+    /// if [Parser::set_keep_comments] is enabled, otherwise they are counted.
     fn erase_whitespace(&mut self) {
         while let Some(c) = self.peek() {
             if c == b' ' || c == b'\t' || c == b'\n' || c == b'\r' {
-                if c == b'\n' {
-                    self.line += 1;
-                    self.line_index = 1;
+                self.next();
+            } else if self.is_indent(b"--") {
+                let start = self.pos;
+                self.next();
+                self.next();
+                match self.long_bracket_level() {
+                    Some(level) => {
+                        self.skip_long_bracket(level);
+                    }
+                    None => {
+                        while let Some(c) = self.peek() {
+                            if c == b'\n' || c == b'\r' {
+                                break;
+                            }
+                            self.next();
+                        }
+                    }
+                }
+                if self.keep_comments {
+                    let comment = String::from_utf8_lossy(&self.str[start..self.pos]);
+                    self.pending_comments.push(comment.to_string());
                 } else {
-                    self.line_index += 1;
+                    self.comments.dropped += 1;
                 }
-                self.eat_char();
             } else {
                 break;
             }
         }
     }
 
+    /// Attach pending comments to the value at `pos`
+    fn attach_comments(&mut self, pos: Vec<usize>) {
+        if !self.pending_comments.is_empty() {
+            let comments = std::mem::take(&mut self.pending_comments);
+            self.comments
+                .values
+                .entry(pos)
+                .or_default()
+                .extend(comments);
+        }
+    }
+
+    /// Consume a byte and track line and column. Columns are counted in
+    /// characters, continuation bytes of UTF-8 do not move the column.
     fn next(&mut self) -> Option<u8> {
         if self.pos < self.len {
             let c = self.str[self.pos];
@@ -171,7 +668,7 @@ impl<'a> Parser<'a> {
             if c == b'\n' {
                 self.line += 1;
                 self.line_index = 1;
-            } else {
+            } else if c & 0xC0 != 0x80 {
                 self.line_index += 1;
             }
             Some(c)
@@ -189,34 +686,53 @@ impl<'a> Parser<'a> {
     }
 
     fn parse_key_val(&mut self) -> Result<Value> {
-        let key = self.get_indent()?;
+        let (key, kind) = self.parse_key()?;
         self.parse_equal()?;
         let val = self.parse_value()?;
-        Ok(Value::KeyVal((key.to_string(), Box::new(val))))
+        Ok(Value::KeyVal((key, Box::new(val), kind)))
     }
 
+    /// Parse key of table field, an identifier, or a string or integer in brackets
+    fn parse_key(&mut self) -> Result<(String, KeyKind)> {
+        self.erase_whitespace();
+        if self.peek() != Some(b'[') {
+            return Ok((self.get_indent()?.to_string(), KeyKind::Name));
+        }
+        self.eat_char();
+        self.erase_whitespace();
+        let key = match self.peek() {
+            Some(b'"' | b'\'' | b'[') => (self.parse_str()?, KeyKind::Str),
+            Some(_) => match self.lex_number()? {
+                (_, Value::Int(i)) => (i.to_string(), KeyKind::Int),
+                (s, _) => return self.error(format!("unsupported key: {}", s)),
+            },
+            None => return self.error("unexpected eof"),
+        };
+        self.erase_whitespace();
+        match self.peek() {
+            Some(b']') => {
+                self.eat_char();
+                Ok(key)
+            }
+            _ => self.expected("']'"),
+        }
+    }
+
+    /// Parse identifier, non-ASCII characters are allowed like LuaJIT does
     fn get_indent(&mut self) -> Result<&'a str> {
         self.erase_whitespace();
         let start = self.pos;
-        let mut is_first = true;
-        let end = loop {
-            match self.peek() {
-                Some(t) => match t {
-                    b'_' | b'a'..=b'z' | b'A'..=b'Z' | b'[' | b']' => self.eat_char(),
-                    b'0'..=b'9' => {
-                        if is_first {
-                            return self.error("unexpected digit");
-                        }
-                        self.eat_char();
-                    }
-                    b' ' | b'\t' | b'=' | b'\n' | b'\r' => break self.pos,
-                    _ => return self.error("unexpected token"),
-                },
-                None => return self.error("unexpected eof"),
+        while let Some(c) = self.peek() {
+            match c {
+                b'_' | b'a'..=b'z' | b'A'..=b'Z' | 0x80.. => self.eat_char(),
+                b'0'..=b'9' if self.pos > start => self.eat_char(),
+                _ => break,
             }
-            is_first = false;
-        };
-        std::str::from_utf8(&self.str[start..end]).map_err(|e| self.error2(e))
+        }
+        if self.pos == start {
+            return self.expected("identifier");
+        }
+        std::str::from_utf8(&self.str[start..self.pos]).map_err(|e| self.error2(e))
     }
 
     fn is_indent(&self, indent: &[u8]) -> bool {
@@ -232,22 +748,53 @@ impl<'a> Parser<'a> {
     }
 
     fn parse_indent(&mut self, indent: &[u8]) -> Result<()> {
-        for c in indent {
-            match self.next() {
-                Some(x) => {
-                    if x != *c {
-                        return self.error("unexpected indent");
-                    }
-                }
-                None => return self.error("unexpected eof"),
-            }
+        if !self.is_indent(indent) {
+            return self.expected(format!("'{}'", String::from_utf8_lossy(indent)));
+        }
+        for _ in indent {
+            self.next();
         }
         Ok(())
     }
 
     fn eat_char(&mut self) {
-        if self.pos < self.len {
-            self.pos += 1;
+        self.next();
+    }
+
+    fn diagnostic<T>(&self, msg: T, expected: Option<String>) -> Diagnostic
+    where
+        T: std::fmt::Display,
+    {
+        let found = match self.str.get(self.pos..) {
+            Some(rest) if !rest.is_empty() => {
+                let rest = String::from_utf8_lossy(&rest[..rest.len().min(4)]);
+                format!(
+                    "'{}'",
+                    rest.chars().next().unwrap_or_default().escape_debug()
+                )
+            }
+            _ => "end of file".to_string(),
+        };
+        let pos = self.pos.min(self.len);
+        let start = self.str[..pos]
+            .iter()
+            .rposition(|c| *c == b'\n')
+            .map_or(0, |p| p + 1);
+        let end = self.str[pos..]
+            .iter()
+            .position(|c| *c == b'\n' || *c == b'\r')
+            .map_or(self.len, |p| pos + p);
+        Diagnostic {
+            line: self.line,
+            column: self.line_index,
+            byte: self.pos,
+            message: msg.to_string(),
+            expected,
+            found,
+            snippet: String::from_utf8_lossy(&self.str[start..end]).to_string(),
+            caret: String::from_utf8_lossy(&self.str[start..pos])
+                .chars()
+                .count(),
         }
     }
 
@@ -255,19 +802,232 @@ impl<'a> Parser<'a> {
     where
         T: std::fmt::Display,
     {
-        anyhow::Error::msg(format!(
-            "Failed to parse at position line {} column {} (byte {}): {}",
-            self.line, self.line_index, self.pos, msg
-        ))
+        anyhow::Error::new(self.diagnostic(msg, None))
     }
 
     fn error<T, A>(&self, msg: T) -> Result<A>
     where
         T: std::fmt::Display,
     {
-        Err(anyhow::Error::msg(format!(
-            "Failed to parse at position line {} column {} (byte {}): {}",
-            self.line, self.line_index, self.pos, msg
-        )))
+        Err(self.error2(msg))
+    }
+
+    fn expected<T, A>(&self, expected: T) -> Result<A>
+    where
+        T: std::fmt::Display,
+    {
+        let msg = format!("expected {}", expected);
+        Err(anyhow::Error::new(
+            self.diagnostic(msg, Some(expected.to_string())),
+        ))
+    }
+}
+
+/// Parse a Lua numeric literal with optional sign, like `-1`, `0x1F`, `.5e-2`,
+/// `0x1p4`, `inf` or `nan`. Decimal integers which do not fit in i64 become floats
+/// and hexadecimal integers wrap around, like Lua does.
+pub fn parse_number(s: &str) -> Option<Value> {
+    let (neg, body) = match s.as_bytes().first() {
+        Some(b'-') => (true, &s[1..]),
+        Some(b'+') => (false, &s[1..]),
+        _ => (false, s),
+    };
+    let body = body.to_ascii_lowercase();
+    let v = match body.as_str() {
+        "inf" | "infinity" => Value::Float(f64::INFINITY),
+        "nan" => Value::Float(f64::NAN),
+        _ => match body.strip_prefix("0x") {
+            Some(hex) => parse_hex(hex)?,
+            None => parse_dec(&body)?,
+        },
+    };
+    Some(match v {
+        Value::Int(i) if neg => Value::Int(i.wrapping_neg()),
+        Value::Float(f) if neg => Value::Float(-f),
+        v => v,
+    })
+}
+
+fn parse_dec(s: &str) -> Option<Value> {
+    let (mantissa, exp) = match s.split_once('e') {
+        Some((m, e)) => (m, Some(e)),
+        None => (s, None),
+    };
+    if !mantissa.bytes().any(|c| c.is_ascii_digit())
+        || !mantissa.bytes().all(|c| c.is_ascii_digit() || c == b'.')
+    {
+        return None;
+    }
+    if let Some(exp) = exp {
+        let digits = exp.strip_prefix(['-', '+']).unwrap_or(exp);
+        if digits.is_empty() || !digits.bytes().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+    }
+    if exp.is_some() || mantissa.contains('.') {
+        s.parse().ok().map(Value::Float)
+    } else {
+        s.parse()
+            .map(Value::Int)
+            .or_else(|_| s.parse().map(Value::Float))
+            .ok()
+    }
+}
+
+fn parse_hex(s: &str) -> Option<Value> {
+    let (mantissa, exp) = match s.split_once('p') {
+        Some((m, e)) => (m, Some(e.parse::<i32>().ok()?)),
+        None => (s, None),
+    };
+    let (int, frac) = match mantissa.split_once('.') {
+        Some((i, f)) => (i, Some(f)),
+        None => (mantissa, None),
+    };
+    let digits = int.len() + frac.map_or(0, |f| f.len());
+    if digits == 0
+        || !int
+            .chars()
+            .chain(frac.unwrap_or("").chars())
+            .all(|c| c.is_ascii_hexdigit())
+    {
+        return None;
+    }
+    if exp.is_none() && frac.is_none() {
+        let mut v: u64 = 0;
+        for c in int.chars() {
+            v = v.wrapping_mul(16).wrapping_add(c.to_digit(16)? as u64);
+        }
+        return Some(Value::Int(v as i64));
+    }
+    let mut v = 0f64;
+    for c in int.chars() {
+        v = v * 16.0 + c.to_digit(16)? as f64;
+    }
+    let mut scale = 1.0 / 16.0;
+    for c in frac.unwrap_or("").chars() {
+        v += c.to_digit(16)? as f64 * scale;
+        scale /= 16.0;
+    }
+    Some(Value::Float(v * 2f64.powi(exp.unwrap_or(0))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_error(src: &str) -> Diagnostic {
+        let e = Parser::new(&src).parse().unwrap_err();
+        e.downcast::<Diagnostic>().unwrap()
+    }
+
+    /// Assert line, column in characters and byte of the diagnostic
+    fn assert_at(d: &Diagnostic, line: usize, column: usize, byte: usize) {
+        assert_eq!(
+            (d.line, d.column, d.byte),
+            (line, column, byte),
+            "wrong location of {}",
+            d
+        );
+    }
+
+    #[test]
+    fn error_column_counts_characters() {
+        let src = "astver = 2.0\nast = {\n    {\"日本語\", @},\n}\n";
+        let d = parse_error(src);
+        assert_at(&d, 3, 13, src.find('@').unwrap());
+        assert_eq!(d.message, "unexpected token");
+        assert_eq!(d.snippet, "    {\"日本語\", @},");
+        assert_eq!(d.caret, 12);
+    }
+
+    #[test]
+    fn error_after_short_string() {
+        let src = "astver = 2.0\nast = {\n    {\"a\\\"b\\u{3042}\\z\n    c\", @},\n}\n";
+        let d = parse_error(src);
+        assert_at(&d, 4, 9, src.find('@').unwrap());
+    }
+
+    #[test]
+    fn error_after_long_string() {
+        let src = "astver = 2.0\nast = {\n    {[==[long\n「長い」]]\n]==], @},\n}\n";
+        let d = parse_error(src);
+        assert_at(&d, 5, 7, src.find('@').unwrap());
+    }
+
+    #[test]
+    fn error_after_comments() {
+        let src = "-- ヘッダー\nastver = 2.0\nast = { --[[ 長い\nコメント ]] @ }\n";
+        let d = parse_error(src);
+        assert_at(&d, 4, 9, src.find('@').unwrap());
+    }
+
+    #[test]
+    fn error_after_crlf() {
+        let src = "astver = 2.0\r\nast = {\r\n    x @\r\n}\r\n";
+        let d = parse_error(src);
+        assert_at(&d, 3, 7, src.find('@').unwrap());
+        assert_eq!(d.message, "expected '='");
+        assert_eq!(d.found, "'@'");
+    }
+
+    #[test]
+    fn error_points_to_escape() {
+        let src = "astver = 2.0\nast = {{\"あ\\q\"}}\n";
+        let d = parse_error(src);
+        assert_at(&d, 2, 11, src.find('\\').unwrap());
+        assert_eq!(d.message, "invalid escape sequence \\q");
+    }
+
+    #[test]
+    fn error_points_to_number() {
+        let src = "astver = 2.0\nast = {\n    bar = 1x,\n}\n";
+        let d = parse_error(src);
+        assert_at(&d, 3, 11, src.find("1x").unwrap());
+        assert_eq!(d.message, "malformed number near '1x'");
+    }
+
+    #[test]
+    fn error_unfinished_string() {
+        let src = "astver = 2.0\nast = {\n    \"ab\n}\n";
+        let d = parse_error(src);
+        assert_at(&d, 3, 8, src.find("\n}").unwrap());
+        assert_eq!(d.message, "unfinished string");
+    }
+
+    #[test]
+    fn error_at_eof() {
+        let src = "astver = 2.0\nast = {\n    {\"テスト\"},\n";
+        let d = parse_error(src);
+        assert_at(&d, 4, 1, src.len());
+        assert_eq!(d.message, "unexpected eof");
+    }
+
+    #[test]
+    fn recover_reports_every_error() {
+        let src = "astver = 2.0\nast = {\n    {\"漢字\", @},\n    k = 1x,\n    ok = 1,\n";
+        let (ast, diagnostics) = Parser::new(&src).parse_recover().unwrap();
+        let locations: Vec<_> = diagnostics
+            .iter()
+            .map(|d| (d.line, d.column, d.byte))
+            .collect();
+        assert_eq!(
+            locations,
+            vec![
+                (3, 12, src.find('@').unwrap()),
+                (4, 9, src.find("1x").unwrap()),
+                (6, 1, src.len()),
+            ]
+        );
+        assert_eq!(ast.ast.find_keyval("ok"), Some(&Value::Int(1)));
+    }
+
+    #[test]
+    fn astver_keeps_spelling() {
+        for src in ["astver = 2\nast = {}\n", "astver = 2.0\nast = {}\n"] {
+            let ast = Parser::new(&src).parse().unwrap();
+            let mut text = Vec::new();
+            crate::dump::Dumper::new(&mut text).dump(&ast).unwrap();
+            assert_eq!(String::from_utf8(text).unwrap(), src);
+        }
     }
 }
@@ -0,0 +1,186 @@
+use crate::graph::{Edge, EdgeKind, NodeId, ScenarioGraph};
+use std::collections::HashMap;
+use std::io::Write;
+
+#[derive(Clone, Copy, Debug)]
+pub enum FlowFormat {
+    /// Graphviz DOT
+    Dot,
+    /// Mermaid flowchart
+    Mermaid,
+}
+
+/// Render branching structure of a file as chart. Blocks are collapsed into
+/// runs (see [ScenarioGraph::runs]), select choices are labelled edges and
+/// targets outside the file are shown as dashed nodes.
+pub struct FlowRenderer {
+    writer: Box<dyn Write>,
+    format: FlowFormat,
+}
+
+impl FlowRenderer {
+    pub fn new<W: Write + 'static>(writer: W, format: FlowFormat) -> Self {
+        FlowRenderer {
+            writer: Box::new(writer),
+            format,
+        }
+    }
+
+    pub fn render(mut self, graph: &ScenarioGraph, file: &str) -> std::io::Result<()> {
+        let runs = graph.runs(file);
+        let mut run_of = HashMap::<&NodeId, usize>::new();
+        for (i, run) in runs.iter().enumerate() {
+            for id in run {
+                run_of.insert(id, i);
+            }
+        }
+        match self.format {
+            FlowFormat::Dot => {
+                writeln!(self.writer, "digraph \"{}\" {{", escape_dot(file))?;
+                writeln!(self.writer, "    node [shape=box];")?;
+            }
+            FlowFormat::Mermaid => writeln!(self.writer, "flowchart TD")?,
+        }
+        for (i, run) in runs.iter().enumerate() {
+            let mut lines = vec![match run.len() {
+                1 => run[0].block.clone(),
+                n => format!("{} … {} ({} blocks)", run[0].block, run[n - 1].block, n),
+            }];
+            if let Some(node) = graph.nodes().get(&run[0])
+                && !node.labels.is_empty()
+            {
+                lines.push(format!("labels: {}", node.labels.join(", ")));
+            }
+            self.render_node(&format!("n{}", i), &lines, false)?;
+        }
+        let mut externals = HashMap::<String, String>::new();
+        for (i, run) in runs.iter().enumerate() {
+            for edge in graph.successors(run.last().unwrap()) {
+                let to = match edge.to.as_ref().and_then(|to| run_of.get(to)) {
+                    Some(j) => format!("n{}", j),
+                    None => {
+                        let label = external_label(edge, file);
+                        match externals.get(&label) {
+                            Some(id) => id.clone(),
+                            None => {
+                                let id = format!("e{}", externals.len());
+                                self.render_node(&id, std::slice::from_ref(&label), true)?;
+                                externals.insert(label, id.clone());
+                                id
+                            }
+                        }
+                    }
+                };
+                let (label, dashed) = match &edge.kind {
+                    EdgeKind::LinkNext => (None, false),
+                    EdgeKind::Select { texts } => (
+                        Some(texts.values().next().map_or("select", |t| t.as_str())),
+                        false,
+                    ),
+                    EdgeKind::ExCall => (Some("excall"), true),
+                };
+                self.render_edge(&format!("n{}", i), &to, label, dashed)?;
+            }
+        }
+        if let FlowFormat::Dot = self.format {
+            writeln!(self.writer, "}}")?;
+        }
+        Ok(())
+    }
+
+    fn render_node(&mut self, id: &str, lines: &[String], external: bool) -> std::io::Result<()> {
+        match self.format {
+            FlowFormat::Dot => {
+                let label: Vec<String> = lines.iter().map(|l| escape_dot(l)).collect();
+                if external {
+                    writeln!(
+                        self.writer,
+                        "    {} [label=\"{}\", shape=ellipse, style=dashed];",
+                        id,
+                        label.join("\\n")
+                    )
+                } else {
+                    writeln!(self.writer, "    {} [label=\"{}\"];", id, label.join("\\n"))
+                }
+            }
+            FlowFormat::Mermaid => {
+                let label: Vec<String> = lines.iter().map(|l| escape_mermaid(l)).collect();
+                if external {
+                    writeln!(self.writer, "    {}([\"{}\"])", id, label.join("<br/>"))
+                } else {
+                    writeln!(self.writer, "    {}[\"{}\"]", id, label.join("<br/>"))
+                }
+            }
+        }
+    }
+
+    fn render_edge(
+        &mut self,
+        from: &str,
+        to: &str,
+        label: Option<&str>,
+        dashed: bool,
+    ) -> std::io::Result<()> {
+        match self.format {
+            FlowFormat::Dot => {
+                let mut attrs = Vec::new();
+                if let Some(label) = label {
+                    attrs.push(format!("label=\"{}\"", escape_dot(label)));
+                }
+                if dashed {
+                    attrs.push("style=dashed".to_string());
+                }
+                if attrs.is_empty() {
+                    writeln!(self.writer, "    {} -> {};", from, to)
+                } else {
+                    writeln!(
+                        self.writer,
+                        "    {} -> {} [{}];",
+                        from,
+                        to,
+                        attrs.join(", ")
+                    )
+                }
+            }
+            FlowFormat::Mermaid => {
+                let arrow = if dashed { "-.->" } else { "-->" };
+                match label {
+                    Some(label) => writeln!(
+                        self.writer,
+                        "    {} {}|\"{}\"| {}",
+                        from,
+                        arrow,
+                        escape_mermaid(label),
+                        to
+                    ),
+                    None => writeln!(self.writer, "    {} {} {}", from, arrow, to),
+                }
+            }
+        }
+    }
+}
+
+/// Label of edge target which is not a block in the rendered file
+fn external_label(edge: &Edge, file: &str) -> String {
+    match (&edge.block, edge.to.is_some()) {
+        (Some(block), _) => format!("{} (missing)", block),
+        (None, resolved) => {
+            let label = edge.label.as_deref().unwrap_or("top");
+            if edge.file != file {
+                format!("{}.ast#{}", edge.file, label)
+            } else if resolved {
+                format!("#{}", label)
+            } else {
+                format!("#{} (missing)", label)
+            }
+        }
+    }
+}
+
+fn escape_dot(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn escape_mermaid(s: &str) -> String {
+    s.replace('"', "#quot;")
+}
@@ -0,0 +1,368 @@
+use crate::graph::file_key;
+use crate::text::{TagPolicy, TextSegment};
+use crate::types::*;
+use std::collections::BTreeMap;
+use std::io::Write;
+
+const STYLE: &str =
+    "body{font-family:sans-serif;line-height:1.8;max-width:1200px;margin:0 auto;padding:1em}
+nav{margin-bottom:1em}
+label{margin-right:1em}
+.msg{display:flex;gap:1em;border-bottom:1px solid #ddd;padding:.3em 0}
+.num{color:#999;min-width:3em;text-align:right}
+.langs{display:flex;flex:1;gap:1em}
+.lang{flex:1}
+.name{font-weight:bold;margin-right:.5em}
+.voice{color:#888;font-family:monospace;font-size:.8em;margin-left:.5em}
+.excall{color:#666}
+.select ul{margin:0;padding-left:1.2em}
+rt{font-size:.6em}
+table{border-collapse:collapse}
+td,th{border:1px solid #ddd;padding:.2em .6em}";
+
+/// Escape text for HTML content and attributes
+fn escape(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&#39;"),
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+/// Language name usable in id and class
+fn lang_class(lang: &str) -> String {
+    lang.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn render_text(segments: &[TextSegment], policy: &TagPolicy, result: &mut String) {
+    for seg in segments {
+        match seg {
+            TextSegment::Text(s) => result.push_str(&escape(s)),
+            TextSegment::LineBreak(_) => result.push_str("<br>"),
+            TextSegment::Ruby { text, .. } => match seg.ruby() {
+                Some(rt) if !rt.is_empty() => {
+                    result.push_str("<ruby>");
+                    render_text(text, policy, result);
+                    result.push_str(&format!("<rt>{}</rt></ruby>", escape(rt)));
+                }
+                _ => render_text(text, policy, result),
+            },
+            TextSegment::Font { text, .. } => render_text(text, policy, result),
+            TextSegment::Tag(tag) => result.push_str(&escape(&policy.render(tag))),
+            TextSegment::KeyVal(_) => {}
+        }
+    }
+}
+
+/// Link to the target of select or excall. Other files are linked to the page
+/// generated for them, which has the same name with `.html` extension.
+fn target_link(file: Option<&str>, label: Option<&str>) -> Option<String> {
+    let anchor = label.map(|l| format!("#{}", escape(l)));
+    match (file, anchor) {
+        (Some(file), anchor) => Some(format!(
+            "{}.html{}",
+            escape(&file_key(file)),
+            anchor.unwrap_or_default()
+        )),
+        (None, anchor) => anchor,
+    }
+}
+
+/// Render messages to a self-contained HTML page. All languages are shown
+/// side by side and can be hidden with the checkboxes on top of the page.
+pub struct HtmlRenderer {
+    writer: Box<dyn Write>,
+    count: usize,
+    voice: bool,
+    index: Option<String>,
+    policy: TagPolicy,
+}
+
+impl HtmlRenderer {
+    pub fn new<W: Write + 'static>(writer: W) -> Self {
+        HtmlRenderer {
+            writer: Box::new(writer),
+            count: 0,
+            voice: false,
+            index: None,
+            policy: TagPolicy::default(),
+        }
+    }
+
+    /// Policy to render tags which are not handled by [TextSegment]
+    pub fn set_tag_policy(&mut self, policy: TagPolicy) {
+        self.policy = policy;
+    }
+
+    /// Show voice files after dialogues
+    pub fn set_voice(&mut self, voice: bool) {
+        self.voice = voice;
+    }
+
+    /// Link to the index page on top of the page
+    pub fn set_index<S: Into<String>>(&mut self, index: Option<S>) {
+        self.index = index.map(|s| s.into());
+    }
+
+    /// Render the page, `name` is used as title if the file does not have a savetitle
+    pub fn render(mut self, name: &str, messages: &Messages) -> anyhow::Result<()> {
+        let mut langs = Vec::<String>::new();
+        let mut add_lang = |lang: &String| {
+            if !langs.contains(lang) {
+                langs.push(lang.clone());
+            }
+        };
+        if let Some(title) = &messages.savetitle {
+            title
+                .keys()
+                .filter(|k| *k != "text")
+                .for_each(&mut add_lang);
+        }
+        for message in &messages.messages {
+            match message {
+                Message::Dialogue(d) => d.dialogues.keys().for_each(&mut add_lang),
+                Message::Select(s) => s.sels.keys().for_each(&mut add_lang),
+                Message::ExCall(_) => {}
+            }
+        }
+        let title = messages
+            .savetitle
+            .as_ref()
+            .and_then(|t| t.first_key_value())
+            .map_or(name, |(_, v)| v.as_str());
+        writeln!(self.writer, "<!DOCTYPE html>")?;
+        writeln!(self.writer, "<html>\n<head>\n<meta charset=\"utf-8\">")?;
+        writeln!(self.writer, "<title>{}</title>", escape(title))?;
+        writeln!(self.writer, "<style>\n{}", STYLE)?;
+        for lang in langs.iter() {
+            let class = lang_class(lang);
+            writeln!(
+                self.writer,
+                "#lang-{0}:not(:checked)~main .lang-{0}{{display:none}}",
+                class
+            )?;
+        }
+        writeln!(self.writer, "</style>\n</head>\n<body>")?;
+        if let Some(index) = &self.index {
+            writeln!(
+                self.writer,
+                "<nav><a href=\"{}\">Index</a></nav>",
+                escape(index)
+            )?;
+        }
+        for lang in langs.iter() {
+            writeln!(
+                self.writer,
+                "<input type=\"checkbox\" id=\"lang-{0}\" checked><label for=\"lang-{0}\">{1}</label>",
+                lang_class(lang),
+                escape(lang)
+            )?;
+        }
+        writeln!(self.writer, "<main>")?;
+        match &messages.savetitle {
+            Some(title) => {
+                write!(self.writer, "<h1>")?;
+                for (lang, t) in title {
+                    write!(
+                        self.writer,
+                        "<span class=\"lang lang-{}\">{}</span> ",
+                        lang_class(lang),
+                        escape(t)
+                    )?;
+                }
+                writeln!(self.writer, "</h1>")?;
+            }
+            None => writeln!(self.writer, "<h1>{}</h1>", escape(name))?,
+        }
+        self.render_speakers(&langs, messages)?;
+        for message in &messages.messages {
+            self.render_message(message)?;
+        }
+        writeln!(self.writer, "</main>\n</body>\n</html>")?;
+        Ok(())
+    }
+
+    fn render_speakers(&mut self, langs: &[String], messages: &Messages) -> std::io::Result<()> {
+        let mut speakers = BTreeMap::<&str, Vec<(&str, usize)>>::new();
+        for message in &messages.messages {
+            if let Message::Dialogue(d) = message {
+                for (lang, ds) in d.dialogues.iter() {
+                    let names = speakers.entry(lang).or_default();
+                    for name in ds.iter().filter_map(|d| d.name.as_deref()) {
+                        match names.iter_mut().find(|(n, _)| *n == name) {
+                            Some((_, count)) => *count += 1,
+                            None => names.push((name, 1)),
+                        }
+                    }
+                }
+            }
+        }
+        if speakers.values().all(|v| v.is_empty()) {
+            return Ok(());
+        }
+        writeln!(
+            self.writer,
+            "<h2>Speakers</h2>\n<div class=\"langs speakers\">"
+        )?;
+        for lang in langs {
+            if let Some(names) = speakers.get(lang.as_str()) {
+                writeln!(
+                    self.writer,
+                    "<div class=\"lang lang-{}\"><table>",
+                    lang_class(lang)
+                )?;
+                for (name, count) in names {
+                    writeln!(
+                        self.writer,
+                        "<tr><td>{}</td><td>{}</td></tr>",
+                        escape(name),
+                        count
+                    )?;
+                }
+                writeln!(self.writer, "</table></div>")?;
+            }
+        }
+        writeln!(self.writer, "</div>\n<h2>Script</h2>")
+    }
+
+    fn begin_message(&mut self, labels: &Option<Vec<String>>) -> std::io::Result<()> {
+        self.count += 1;
+        write!(self.writer, "<div class=\"msg\">")?;
+        if let Some(labels) = labels {
+            for label in labels.iter() {
+                write!(self.writer, "<a id=\"{}\"></a>", escape(label))?;
+            }
+        }
+        write!(self.writer, "<span class=\"num\">{}</span>", self.count)
+    }
+
+    fn render_message(&mut self, message: &Message) -> std::io::Result<()> {
+        match message {
+            Message::Dialogue(dialogue) => {
+                self.begin_message(&dialogue.labels)?;
+                write!(self.writer, "<div class=\"langs\">")?;
+                for (lang, ds) in dialogue.dialogues.iter() {
+                    write!(
+                        self.writer,
+                        "<div class=\"lang lang-{}\" lang=\"{}\">",
+                        lang_class(lang),
+                        escape(lang)
+                    )?;
+                    for d in ds {
+                        let mut text = String::new();
+                        render_text(&d.segments, &self.policy, &mut text);
+                        write!(self.writer, "<p>")?;
+                        if let Some(name) = &d.name {
+                            write!(self.writer, "<span class=\"name\">{}</span>", escape(name))?;
+                        }
+                        write!(self.writer, "{}", text)?;
+                        if self.voice {
+                            for v in d.voices.iter() {
+                                if let Some(file) = &v.file {
+                                    let voice = match &v.ch {
+                                        Some(ch) => format!("{}:{}", ch, file),
+                                        None => file.clone(),
+                                    };
+                                    write!(
+                                        self.writer,
+                                        "<span class=\"voice\">{}</span>",
+                                        escape(&voice)
+                                    )?;
+                                }
+                            }
+                        }
+                        write!(self.writer, "</p>")?;
+                    }
+                    write!(self.writer, "</div>")?;
+                }
+                writeln!(self.writer, "</div></div>")?;
+            }
+            Message::ExCall(excall) => {
+                self.begin_message(&excall.labels)?;
+                write!(self.writer, "<div class=\"excall\">")?;
+                for excall in &excall.excalls {
+                    let text = match (&excall.file, &excall.label) {
+                        (Some(file), Some(label)) => format!("{}#{}", file, label),
+                        (Some(file), None) => file.clone(),
+                        (None, Some(label)) => format!("#{}", label),
+                        (None, None) => continue,
+                    };
+                    match target_link(excall.file.as_deref(), excall.label.as_deref()) {
+                        Some(link) => write!(
+                            self.writer,
+                            "<div>&rarr; <a href=\"{}\">{}</a></div>",
+                            link,
+                            escape(&text)
+                        )?,
+                        None => write!(self.writer, "<div>&rarr; {}</div>", escape(&text))?,
+                    }
+                }
+                writeln!(self.writer, "</div></div>")?;
+            }
+            Message::Select(select) => {
+                self.begin_message(&select.labels)?;
+                write!(self.writer, "<div class=\"langs select\">")?;
+                for (lang, sels) in select.sels.iter() {
+                    write!(
+                        self.writer,
+                        "<div class=\"lang lang-{}\" lang=\"{}\"><ul>",
+                        lang_class(lang),
+                        escape(lang)
+                    )?;
+                    for sel in sels {
+                        match target_link(sel.file.as_deref(), sel.label.as_deref()) {
+                            Some(link) => write!(
+                                self.writer,
+                                "<li><a href=\"{}\">{}</a></li>",
+                                link,
+                                escape(&sel.text)
+                            )?,
+                            None => write!(self.writer, "<li>{}</li>", escape(&sel.text))?,
+                        }
+                    }
+                    write!(self.writer, "</ul></div>")?;
+                }
+                writeln!(self.writer, "</div></div>")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Render the index page of a directory, `pages` are the names of pages
+/// (without `.html`) and their titles.
+pub fn render_html_index<W: Write>(
+    mut writer: W,
+    title: &str,
+    pages: &[(String, Option<String>)],
+) -> std::io::Result<()> {
+    writeln!(writer, "<!DOCTYPE html>")?;
+    writeln!(writer, "<html>\n<head>\n<meta charset=\"utf-8\">")?;
+    writeln!(writer, "<title>{}</title>", escape(title))?;
+    writeln!(writer, "<style>\n{}\n</style>\n</head>\n<body>", STYLE)?;
+    writeln!(writer, "<h1>{}</h1>\n<table>", escape(title))?;
+    for (name, page_title) in pages {
+        writeln!(
+            writer,
+            "<tr><td><a href=\"{0}.html\">{0}</a></td><td>{1}</td></tr>",
+            escape(name),
+            escape(page_title.as_deref().unwrap_or(""))
+        )?;
+    }
+    writeln!(writer, "</table>\n</body>\n</html>")
+}
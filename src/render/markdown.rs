@@ -1,10 +1,79 @@
+use crate::text::{TagPolicy, TextSegment, font_tag};
 use crate::types::*;
 use std::io::Write;
 
+fn render_text(
+    segments: &[TextSegment],
+    policy: &TagPolicy,
+    line_break: &str,
+    result: &mut String,
+) {
+    for seg in segments {
+        match seg {
+            TextSegment::Text(s) => result.push_str(s),
+            TextSegment::LineBreak(_) => result.push_str(line_break),
+            TextSegment::Ruby { text, .. } => match seg.ruby() {
+                Some(rt) if !rt.is_empty() => {
+                    result.push_str("<ruby>");
+                    render_text(text, policy, line_break, result);
+                    result.push_str(&format!("<rt>{}</rt></ruby>", rt));
+                }
+                _ => render_text(text, policy, line_break, result),
+            },
+            TextSegment::Font { open, text, close } => {
+                result.push_str(&font_tag(open, false));
+                render_text(text, policy, line_break, result);
+                if let Some(close) = close {
+                    result.push_str(&font_tag(close, true));
+                }
+            }
+            TextSegment::Tag(tag) => result.push_str(&policy.render(tag)),
+            TextSegment::KeyVal(_) => {}
+        }
+    }
+}
+
+fn dialogue_text(d: &Dialogue, policy: &TagPolicy, voice: bool, line_break: &str) -> String {
+    let mut text = String::new();
+    render_text(&d.segments, policy, line_break, &mut text);
+    let mut text = text.trim_end().to_string();
+    if voice {
+        for v in d.voices.iter() {
+            if let Some(file) = &v.file {
+                match &v.ch {
+                    Some(ch) => text.push_str(&format!(" `{}:{}`", ch, file)),
+                    None => text.push_str(&format!(" `{}`", file)),
+                }
+            }
+        }
+    }
+    text
+}
+
+fn target_link(text: &str, file: &Option<String>, label: &Option<String>) -> String {
+    match (file, label) {
+        (Some(file), Some(label)) => format!("[{}]({}.ast#{})", text, file, label),
+        (Some(file), None) => format!("[{}]({}.ast)", text, file),
+        (None, Some(label)) => format!("[{}](#{})", text, label),
+        (None, None) => text.to_string(),
+    }
+}
+
+/// Escape text for a cell of table, raw newlines in strings would end the row
+fn table_cell(s: &str) -> String {
+    s.replace('|', "\\|")
+        .replace("\r\n", "<br>")
+        .replace('\n', "<br>")
+}
+
 pub struct MarkdownRenderer {
     writer: Box<dyn Write>,
     count: usize,
     language: Option<String>,
+    /// Render a table with a column for each language
+    languages: Vec<String>,
+    voice: bool,
+    policy: TagPolicy,
 }
 
 impl MarkdownRenderer {
@@ -13,10 +82,37 @@ impl MarkdownRenderer {
             writer: Box::new(writer),
             count: 0,
             language: None,
+            languages: Vec::new(),
+            voice: false,
+            policy: TagPolicy::default(),
+        }
+    }
+
+    /// Policy to render tags which are not handled by [TextSegment]
+    pub fn set_tag_policy(&mut self, policy: TagPolicy) {
+        self.policy = policy;
+    }
+
+    /// Show voice files after dialogues
+    pub fn set_voice(&mut self, voice: bool) {
+        self.voice = voice;
+    }
+
+    /// Languages to render. If more than one language is given, messages are
+    /// rendered as a table with a column for each language, otherwise the
+    /// first language found is used.
+    pub fn set_languages(&mut self, languages: Vec<String>) {
+        if languages.len() > 1 {
+            self.languages = languages;
+        } else {
+            self.language = languages.into_iter().next();
         }
     }
 
     pub fn render(mut self, messages: &Messages) -> anyhow::Result<()> {
+        if !self.languages.is_empty() {
+            return Ok(self.render_table(messages)?);
+        }
         if let Some(title) = &messages.savetitle {
             let title = if let Some(lang) = &self.language {
                 title.get(lang).or_else(|| title.get("text"))
@@ -63,7 +159,7 @@ impl MarkdownRenderer {
                 if let Some(ds) = d {
                     if ds.len() == 1 {
                         let d = &ds[0];
-                        let text = d.text.trim_end().replace("\n", "  \n");
+                        let text = dialogue_text(d, &self.policy, self.voice, "  \n");
                         if let Some(name) = &d.name {
                             writeln!(self.writer, "{}: {}", name, text)?;
                         } else {
@@ -72,7 +168,7 @@ impl MarkdownRenderer {
                     } else {
                         writeln!(self.writer, "")?;
                         for d in ds {
-                            let text = d.text.trim_end().replace("\n", "  \n");
+                            let text = dialogue_text(d, &self.policy, self.voice, "  \n");
                             if let Some(name) = &d.name {
                                 writeln!(self.writer, "  - {}: {}", name, text)?;
                             } else {
@@ -148,4 +244,92 @@ impl MarkdownRenderer {
         }
         Ok(())
     }
+
+    fn render_table(&mut self, messages: &Messages) -> std::io::Result<()> {
+        if let Some(title) = &messages.savetitle {
+            let titles: Vec<&str> = self
+                .languages
+                .iter()
+                .filter_map(|lang| title.get(lang).or_else(|| title.get("text")))
+                .map(|t| t.as_str())
+                .collect();
+            if !titles.is_empty() {
+                writeln!(self.writer, "# {}", titles.join(" / "))?;
+                writeln!(self.writer)?;
+            }
+        }
+        writeln!(self.writer, "| # | {} |", self.languages.join(" | "))?;
+        writeln!(self.writer, "|---|{}", "---|".repeat(self.languages.len()))?;
+        for message in &messages.messages {
+            let (labels, cells) = match message {
+                Message::Dialogue(dialogue) => {
+                    let cells = self
+                        .languages
+                        .iter()
+                        .map(|lang| {
+                            let ds = match dialogue.dialogues.get(lang) {
+                                Some(ds) => ds,
+                                None => return String::new(),
+                            };
+                            let texts: Vec<String> = ds
+                                .iter()
+                                .map(|d| {
+                                    let text = dialogue_text(d, &self.policy, self.voice, "<br>");
+                                    match &d.name {
+                                        Some(name) => format!("{}: {}", name, text),
+                                        None => text,
+                                    }
+                                })
+                                .collect();
+                            table_cell(&texts.join("<br>"))
+                        })
+                        .collect();
+                    (&dialogue.labels, cells)
+                }
+                Message::ExCall(excall) => {
+                    let links: Vec<String> = excall
+                        .excalls
+                        .iter()
+                        .filter(|e| e.file.is_some() || e.label.is_some())
+                        .map(|e| {
+                            let text = e.file.as_deref().or(e.label.as_deref()).unwrap();
+                            target_link(text, &e.file, &e.label)
+                        })
+                        .collect();
+                    // Excalls do not have text, so they are only shown in the first column
+                    let mut cells = vec![String::new(); self.languages.len()];
+                    cells[0] = table_cell(&links.join("<br>"));
+                    (&excall.labels, cells)
+                }
+                Message::Select(select) => {
+                    let cells = self
+                        .languages
+                        .iter()
+                        .map(|lang| {
+                            let links: Vec<String> = select
+                                .sels
+                                .get(lang)
+                                .map(|sels| {
+                                    sels.iter()
+                                        .map(|s| target_link(&s.text, &s.file, &s.label))
+                                        .collect()
+                                })
+                                .unwrap_or_default();
+                            table_cell(&links.join("<br>"))
+                        })
+                        .collect();
+                    (&select.labels, cells)
+                }
+            };
+            self.count += 1;
+            write!(self.writer, "| ")?;
+            if let Some(labels) = labels {
+                for label in labels.iter() {
+                    write!(self.writer, "<a name=\"{}\"></a>", label)?;
+                }
+            }
+            writeln!(self.writer, "{} | {} |", self.count, cells.join(" | "))?;
+        }
+        Ok(())
+    }
 }
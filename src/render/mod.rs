@@ -1,3 +1,7 @@
+pub mod flow;
+pub mod html;
 pub mod markdown;
 
+pub use flow::{FlowFormat, FlowRenderer};
+pub use html::{HtmlRenderer, render_html_index};
 pub use markdown::MarkdownRenderer;
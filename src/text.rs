@@ -0,0 +1,779 @@
+use crate::types::Value;
+use std::collections::BTreeMap;
+
+/// An inline tag in dialogue text, like `{"rt2"}` or `{"ruby", text="..."}`
+#[derive(Clone, Debug)]
+pub struct Tag {
+    pub name: String,
+    /// Members after the tag name, usually keyvals
+    pub attrs: Vec<Value>,
+}
+
+impl Tag {
+    pub fn new<S: Into<String>>(name: S) -> Self {
+        Tag {
+            name: name.into(),
+            attrs: Vec::new(),
+        }
+    }
+
+    pub fn from_value(v: &Value) -> Option<Self> {
+        match v {
+            Value::Array(arr) => match arr.first() {
+                Some(Value::Str(name)) => Some(Tag {
+                    name: name.clone(),
+                    attrs: arr[1..].to_vec(),
+                }),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        let mut arr = Vec::with_capacity(self.attrs.len() + 1);
+        arr.push(Value::Str(self.name.clone()));
+        arr.extend(self.attrs.iter().cloned());
+        Value::Array(arr)
+    }
+
+    pub fn get_attr(&self, key: &str) -> Option<&Value> {
+        self.attrs.iter().find_map(|v| v.find_keyval(key))
+    }
+
+    pub fn set_attr(&mut self, key: &str, value: Value) {
+        match self.attrs.iter_mut().find_map(|v| v.keyval_entry_mut(key)) {
+            Some(v) => *v = value,
+            None => self.attrs.push(Value::key_val(key, value)),
+        }
+    }
+
+    /// Render the tag like `[tag:name k=v]`. Strings which contain spaces,
+    /// quotes or brackets, or look like other values, are quoted like `k="a b"`.
+    pub fn to_placeholder(&self) -> String {
+        let mut s = format!("[tag:{}", placeholder_str(&self.name));
+        for v in &self.attrs {
+            s.push(' ');
+            match v {
+                Value::KeyVal((k, v, _)) => {
+                    s.push_str(&placeholder_str(k));
+                    s.push('=');
+                    s.push_str(&placeholder_value(v));
+                }
+                v => s.push_str(&placeholder_value(v)),
+            }
+        }
+        s.push(']');
+        s
+    }
+
+    /// Parse a placeholder at the start of `s`, like `[tag:name k=v k2="a b"]`.
+    /// Returns the tag and the text after the placeholder.
+    fn parse_placeholder(s: &str) -> anyhow::Result<(Self, &str)> {
+        let err = || anyhow::anyhow!("invalid placeholder: {}", s);
+        let rest = s.strip_prefix("[tag:").ok_or_else(err)?;
+        let (name, _, mut rest) = placeholder_token(rest).ok_or_else(err)?;
+        let mut tag = Tag::new(name);
+        loop {
+            if let Some(r) = rest.strip_prefix(']') {
+                return Ok((tag, r));
+            }
+            rest = rest.strip_prefix(' ').ok_or_else(err)?;
+            let (token, quoted, r) = placeholder_token(rest).ok_or_else(err)?;
+            rest = r;
+            tag.attrs.push(match rest.strip_prefix('=') {
+                Some(r) => {
+                    let (v, quoted, r) = placeholder_token(r).ok_or_else(err)?;
+                    rest = r;
+                    Value::key_val(token, placeholder_token_value(v, quoted))
+                }
+                None => placeholder_token_value(token, quoted),
+            });
+        }
+    }
+
+    /// Keyvals of the tag, values are converted to string
+    pub fn keyvals(&self) -> Vec<(&str, String)> {
+        self.attrs
+            .iter()
+            .filter_map(|v| match v {
+                Value::KeyVal((k, v, _)) => Some((k.as_str(), value_to_string(v))),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// How a tag is rendered into text
+#[derive(Clone, Debug)]
+pub enum TagRender {
+    /// Drop the tag
+    Ignore,
+    /// Render the tag as placeholder like `[tag:name k=v]`,
+    /// which can be converted back to the tag
+    Placeholder,
+    /// Render the tag as fixed text
+    Text(String),
+}
+
+impl std::str::FromStr for TagRender {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(TagRender::Ignore),
+            "placeholder" => Ok(TagRender::Placeholder),
+            _ => match s.strip_prefix("text:") {
+                Some(t) => Ok(TagRender::Text(t.to_string())),
+                None => Err(anyhow::anyhow!(
+                    "invalid tag render {}, expected ignore, placeholder or text:<TEXT>",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+/// How tags which are not in the registry are handled
+#[derive(Clone, Copy, Debug)]
+pub enum UnknownTagMode {
+    /// Fail to extract messages
+    Error,
+    /// Render as placeholder and report a warning
+    Placeholder,
+    /// Drop the tag and report a warning
+    Ignore,
+}
+
+/// Rendering policy for tags which are not handled by [TextSegment]
+#[derive(Clone, Debug)]
+pub struct TagPolicy {
+    tags: BTreeMap<String, TagRender>,
+    unknown: UnknownTagMode,
+}
+
+impl Default for TagPolicy {
+    fn default() -> Self {
+        let mut tags = BTreeMap::new();
+        for name in [
+            "wait",
+            "font",
+            "/font",
+            "color",
+            "size",
+            "indent",
+            "endindent",
+        ] {
+            tags.insert(name.to_string(), TagRender::Placeholder);
+        }
+        TagPolicy {
+            tags,
+            unknown: UnknownTagMode::Placeholder,
+        }
+    }
+}
+
+impl TagPolicy {
+    /// Register a known tag with its rendering
+    pub fn set_tag<S: Into<String>>(&mut self, name: S, render: TagRender) {
+        self.tags.insert(name.into(), render);
+    }
+
+    pub fn set_unknown_mode(&mut self, mode: UnknownTagMode) {
+        self.unknown = mode;
+    }
+
+    pub fn render(&self, tag: &Tag) -> String {
+        let render = match self.tags.get(&tag.name) {
+            Some(r) => r,
+            None => match self.unknown {
+                UnknownTagMode::Placeholder => &TagRender::Placeholder,
+                _ => &TagRender::Ignore,
+            },
+        };
+        match render {
+            TagRender::Ignore => String::new(),
+            TagRender::Placeholder => tag.to_placeholder(),
+            TagRender::Text(t) => t.clone(),
+        }
+    }
+
+    /// Check tags in segments. Returns names of unknown tags,
+    /// or error if unknown tags are not allowed.
+    pub fn check<'a>(&self, segments: &'a [TextSegment]) -> Result<Vec<&'a str>, &'a Tag> {
+        let mut unknown = Vec::new();
+        for tag in TextSegment::tags(segments) {
+            if !self.tags.contains_key(&tag.name) {
+                if let UnknownTagMode::Error = self.unknown {
+                    return Err(tag);
+                }
+                if !unknown.contains(&tag.name.as_str()) {
+                    unknown.push(tag.name.as_str());
+                }
+            }
+        }
+        Ok(unknown)
+    }
+}
+
+/// A segment of dialogue text. Converting segments back to values
+/// produces exactly the values they were parsed from.
+#[derive(Clone, Debug)]
+pub enum TextSegment {
+    /// Plain text
+    Text(String),
+    /// Line break (`rt2` or `ret2`)
+    LineBreak(Tag),
+    /// Text with ruby (`ruby` ... `/ruby` or `txruby` ... `txruby`)
+    Ruby {
+        open: Tag,
+        text: Vec<TextSegment>,
+        /// None if the ruby is not closed in the dialogue
+        close: Option<Tag>,
+    },
+    /// Text in special font (`exfont` ... `exfont`)
+    Font {
+        open: Tag,
+        text: Vec<TextSegment>,
+        /// None if the font is not closed in the dialogue
+        close: Option<Tag>,
+    },
+    /// Any other tag
+    Tag(Tag),
+    /// Keyed member, like `name = {"主人公"}`. It is not rendered.
+    KeyVal(Value),
+}
+
+enum SpanKind {
+    Ruby,
+    Font,
+}
+
+impl TextSegment {
+    /// Parse members of a dialogue. Keyvals (like `name`) are kept in place as [TextSegment::KeyVal].
+    pub fn parse(values: &[Value]) -> anyhow::Result<Vec<TextSegment>> {
+        let mut root = Vec::new();
+        let mut stack: Vec<(SpanKind, Tag, Vec<TextSegment>)> = Vec::new();
+        for v in values {
+            let tag = match v {
+                Value::Str(s) => {
+                    let cur = stack.last_mut().map_or(&mut root, |(_, _, t)| t);
+                    cur.push(TextSegment::Text(s.clone()));
+                    continue;
+                }
+                Value::KeyVal(_) => {
+                    let cur = stack.last_mut().map_or(&mut root, |(_, _, t)| t);
+                    cur.push(TextSegment::KeyVal(v.clone()));
+                    continue;
+                }
+                _ => Tag::from_value(v).ok_or(anyhow::anyhow!("{:?}", v))?,
+            };
+            let in_ruby = matches!(stack.last(), Some((SpanKind::Ruby, _, _)));
+            let in_font = matches!(stack.last(), Some((SpanKind::Font, _, _)));
+            let close = match tag.name.as_str() {
+                "rt2" | "ret2" => {
+                    let cur = stack.last_mut().map_or(&mut root, |(_, _, t)| t);
+                    cur.push(TextSegment::LineBreak(tag));
+                    continue;
+                }
+                "ruby" => {
+                    stack.push((SpanKind::Ruby, tag, Vec::new()));
+                    continue;
+                }
+                "txruby" if !in_ruby => {
+                    stack.push((SpanKind::Ruby, tag, Vec::new()));
+                    continue;
+                }
+                "exfont" if !in_font => {
+                    stack.push((SpanKind::Font, tag, Vec::new()));
+                    continue;
+                }
+                "/ruby" | "txruby" if in_ruby => tag,
+                "exfont" => tag,
+                _ => {
+                    let cur = stack.last_mut().map_or(&mut root, |(_, _, t)| t);
+                    cur.push(TextSegment::Tag(tag));
+                    continue;
+                }
+            };
+            let (kind, open, text) = stack.pop().unwrap();
+            let seg = kind.into_segment(open, text, Some(close));
+            stack.last_mut().map_or(&mut root, |(_, _, t)| t).push(seg);
+        }
+        while let Some((kind, open, text)) = stack.pop() {
+            let seg = kind.into_segment(open, text, None);
+            stack.last_mut().map_or(&mut root, |(_, _, t)| t).push(seg);
+        }
+        Ok(root)
+    }
+
+    /// Convert segments back to dialogue values
+    pub fn to_values(segments: &[TextSegment]) -> Vec<Value> {
+        let mut result = Vec::new();
+        for seg in segments {
+            seg.push_values(&mut result);
+        }
+        result
+    }
+
+    fn push_values(&self, result: &mut Vec<Value>) {
+        match self {
+            TextSegment::Text(s) => result.push(Value::Str(s.clone())),
+            TextSegment::LineBreak(tag) | TextSegment::Tag(tag) => result.push(tag.to_value()),
+            TextSegment::KeyVal(v) => result.push(v.clone()),
+            TextSegment::Ruby { open, text, close } | TextSegment::Font { open, text, close } => {
+                result.push(open.to_value());
+                for seg in text {
+                    seg.push_values(result);
+                }
+                if let Some(close) = close {
+                    result.push(close.to_value());
+                }
+            }
+        }
+    }
+
+    /// Ruby text of a ruby span
+    pub fn ruby(&self) -> Option<&str> {
+        match self {
+            TextSegment::Ruby { open, .. } => open.get_attr("text").and_then(|v| v.as_str()),
+            _ => None,
+        }
+    }
+
+    /// All other tags in segments, see [TextSegment::Tag]
+    pub fn tags(segments: &[TextSegment]) -> Vec<&Tag> {
+        let mut result = Vec::new();
+        for seg in segments {
+            match seg {
+                TextSegment::Tag(tag) => result.push(tag),
+                TextSegment::Ruby { text, .. } | TextSegment::Font { text, .. } => {
+                    result.extend(Self::tags(text))
+                }
+                _ => {}
+            }
+        }
+        result
+    }
+
+    /// All keyed members in segments, see [TextSegment::KeyVal]
+    pub fn keyvals(segments: &[TextSegment]) -> Vec<&Value> {
+        let mut result = Vec::new();
+        for seg in segments {
+            match seg {
+                TextSegment::KeyVal(v) => result.push(v),
+                TextSegment::Ruby { text, .. } | TextSegment::Font { text, .. } => {
+                    result.extend(Self::keyvals(text))
+                }
+                _ => {}
+            }
+        }
+        result
+    }
+
+    /// Render segments to text with `\n` for line breaks, `<ruby>` and `<exfont>` tags.
+    /// Other tags are rendered by `policy`. Literal `[tag:` in text is escaped as `[[tag:`,
+    /// so it is not mistaken for a placeholder.
+    pub fn to_html(segments: &[TextSegment], policy: &TagPolicy) -> String {
+        let mut result = String::new();
+        for seg in segments {
+            seg.push_html(&mut result, policy);
+        }
+        result
+    }
+
+    fn push_html(&self, result: &mut String, policy: &TagPolicy) {
+        match self {
+            TextSegment::Text(s) => result.push_str(&s.replace("[tag:", "[[tag:")),
+            TextSegment::LineBreak(_) => result.push('\n'),
+            TextSegment::Ruby { text, .. } => {
+                result.push_str("<ruby>");
+                result.push_str(&Self::to_html(text, policy));
+                result.push_str(&format!("<rt>{}</rt></ruby>", self.ruby().unwrap_or("")));
+            }
+            TextSegment::Font { open, text, close } => {
+                result.push_str(&font_tag(open, false));
+                result.push_str(&Self::to_html(text, policy));
+                if let Some(close) = close {
+                    result.push_str(&font_tag(close, true));
+                }
+            }
+            TextSegment::Tag(tag) => result.push_str(&policy.render(tag)),
+            TextSegment::KeyVal(_) => {}
+        }
+    }
+
+    /// Parse text generated by [TextSegment::to_html].
+    ///
+    /// Tag names and attributes are taken from `template` (usually the
+    /// segments of the original dialogue) where possible, so that the
+    /// original tag layout is kept. Placeholders of other tags are converted
+    /// back to tags, tags rendered as fixed text or ignored are lost.
+    pub fn from_html(text: &str, template: &[TextSegment]) -> anyhow::Result<Vec<TextSegment>> {
+        let mut tpl = Template::default();
+        tpl.collect(template);
+        let line_break = tpl.line_break.clone().unwrap_or_else(|| Tag::new("rt2"));
+        let mut builder = HtmlBuilder::default();
+        let mut rest = text;
+        while let Some(c) = rest.chars().next() {
+            if c == '\n' {
+                builder.push(TextSegment::LineBreak(line_break.clone()));
+                rest = &rest[1..];
+            } else if let Some(r) = rest.strip_prefix("<ruby>") {
+                let (base, r) = r
+                    .split_once("<rt>")
+                    .ok_or(anyhow::anyhow!("missing <rt> in ruby: {}", rest))?;
+                let (rt, r) = r
+                    .split_once("</rt></ruby>")
+                    .ok_or(anyhow::anyhow!("missing </rt></ruby> in ruby: {}", rest))?;
+                let mut open = tpl.ruby_open.clone().unwrap_or_else(|| Tag::new("ruby"));
+                // A ruby without `text` is rendered with empty `<rt>`
+                if !rt.is_empty() || open.get_attr("text").is_some() {
+                    open.set_attr("text", Value::Str(rt.to_string()));
+                }
+                let close = tpl.ruby_close.clone().unwrap_or_else(|| {
+                    Tag::new(if open.name == "txruby" {
+                        "txruby"
+                    } else {
+                        "/ruby"
+                    })
+                });
+                let mut text = Vec::new();
+                if !base.is_empty() {
+                    text.push(TextSegment::Text(base.to_string()));
+                }
+                builder.push(TextSegment::Ruby {
+                    open,
+                    text,
+                    close: Some(close),
+                });
+                rest = r;
+            } else if let Some(r) = rest.strip_prefix("<exfont") {
+                let (attrs, r) = r
+                    .split_once('>')
+                    .ok_or(anyhow::anyhow!("missing '>' in exfont: {}", rest))?;
+                builder.open_font(tpl.find_font(&rest[..rest.len() - r.len()], attrs)?);
+                rest = r;
+            } else if let Some(r) = rest.strip_prefix("[[tag:") {
+                builder.buf.push_str("[tag:");
+                rest = r;
+            } else if rest.starts_with("[tag:") {
+                let (tag, r) = Tag::parse_placeholder(rest)?;
+                let placeholder = &rest[..rest.len() - r.len()];
+                let tag = match tpl.tags.iter().find(|t| t.to_placeholder() == placeholder) {
+                    Some(tag) => tag.clone(),
+                    None => tag,
+                };
+                builder.push(TextSegment::Tag(tag));
+                rest = r;
+            } else if let Some(r) = rest.strip_prefix("</exfont") {
+                let (attrs, r) = r
+                    .split_once('>')
+                    .ok_or(anyhow::anyhow!("missing '>' in exfont: {}", rest))?;
+                let close = tpl.find_font(&rest[..rest.len() - r.len()], attrs)?;
+                if !builder.close_font(close) {
+                    return Err(anyhow::anyhow!("unexpected closing exfont: {}", rest));
+                }
+                rest = r;
+            } else {
+                builder.buf.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+        Ok(builder.finish())
+    }
+}
+
+/// Builds segments while parsing html
+#[derive(Default)]
+struct HtmlBuilder {
+    result: Vec<TextSegment>,
+    font: Option<(Tag, Vec<TextSegment>)>,
+    buf: String,
+}
+
+impl HtmlBuilder {
+    fn flush(&mut self) {
+        if !self.buf.is_empty() {
+            let seg = TextSegment::Text(std::mem::take(&mut self.buf));
+            self.push_raw(seg);
+        }
+    }
+
+    fn push_raw(&mut self, seg: TextSegment) {
+        match &mut self.font {
+            Some((_, text)) => text.push(seg),
+            None => self.result.push(seg),
+        }
+    }
+
+    fn push(&mut self, seg: TextSegment) {
+        self.flush();
+        self.push_raw(seg);
+    }
+
+    fn open_font(&mut self, open: Tag) {
+        self.flush();
+        if let Some((open, text)) = self.font.take() {
+            self.result.push(TextSegment::Font {
+                open,
+                text,
+                close: None,
+            });
+        }
+        self.font = Some((open, Vec::new()));
+    }
+
+    fn close_font(&mut self, close: Tag) -> bool {
+        self.flush();
+        match self.font.take() {
+            Some((open, text)) => {
+                self.result.push(TextSegment::Font {
+                    open,
+                    text,
+                    close: Some(close),
+                });
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn finish(mut self) -> Vec<TextSegment> {
+        self.flush();
+        if let Some((open, text)) = self.font.take() {
+            self.result.push(TextSegment::Font {
+                open,
+                text,
+                close: None,
+            });
+        }
+        self.result
+    }
+}
+
+impl SpanKind {
+    fn into_segment(self, open: Tag, text: Vec<TextSegment>, close: Option<Tag>) -> TextSegment {
+        match self {
+            SpanKind::Ruby => TextSegment::Ruby { open, text, close },
+            SpanKind::Font => TextSegment::Font { open, text, close },
+        }
+    }
+}
+
+/// Render a font tag as inline HTML, like `<exfont size="20">` or `</exfont>`
+pub fn font_tag(tag: &Tag, close: bool) -> String {
+    let mut s = String::from(if close { "</" } else { "<" });
+    s.push_str(&tag.name);
+    for (k, v) in tag.keyvals() {
+        s.push_str(&format!(" {}=\"{}\"", k, v));
+    }
+    s.push('>');
+    s
+}
+
+/// Tags of the original dialogue which are reused when parsing html
+#[derive(Default)]
+struct Template {
+    line_break: Option<Tag>,
+    ruby_open: Option<Tag>,
+    ruby_close: Option<Tag>,
+    fonts: Vec<(String, Tag)>,
+    tags: Vec<Tag>,
+}
+
+impl Template {
+    fn collect(&mut self, segments: &[TextSegment]) {
+        for seg in segments {
+            match seg {
+                TextSegment::LineBreak(tag) if self.line_break.is_none() => {
+                    self.line_break = Some(tag.clone());
+                }
+                TextSegment::Ruby { open, text, close } => {
+                    if self.ruby_open.is_none() {
+                        self.ruby_open = Some(open.clone());
+                        self.ruby_close = close.clone();
+                    }
+                    self.collect(text);
+                }
+                TextSegment::Font { open, text, close } => {
+                    self.fonts.push((font_tag(open, false), open.clone()));
+                    if let Some(close) = close {
+                        self.fonts.push((font_tag(close, true), close.clone()));
+                    }
+                    self.collect(text);
+                }
+                TextSegment::Tag(tag) => self.tags.push(tag.clone()),
+                _ => {}
+            }
+        }
+    }
+
+    /// Find the font tag which renders to `html`, or build a new one from `attrs`
+    fn find_font(&self, html: &str, attrs: &str) -> anyhow::Result<Tag> {
+        if let Some((_, tag)) = self.fonts.iter().find(|(h, _)| h == html) {
+            return Ok(tag.clone());
+        }
+        let mut tag = Tag::new("exfont");
+        tag.attrs = parse_attrs(attrs)?;
+        Ok(tag)
+    }
+}
+
+/// Parse attributes like ` size="20" color="red"`
+fn parse_attrs(attrs: &str) -> anyhow::Result<Vec<Value>> {
+    let mut result = Vec::new();
+    let mut rest = attrs.trim_start();
+    while !rest.is_empty() {
+        let (k, r) = rest
+            .split_once("=\"")
+            .ok_or(anyhow::anyhow!("invalid attribute: {}", rest))?;
+        let (v, r) = r
+            .split_once('"')
+            .ok_or(anyhow::anyhow!("invalid attribute: {}", rest))?;
+        result.push(Value::key_val(k.trim(), parse_attr_value(v)));
+        rest = r.trim_start();
+    }
+    Ok(result)
+}
+
+fn parse_attr_value(v: &str) -> Value {
+    if let Ok(b) = v.parse::<bool>() {
+        Value::Bool(b)
+    } else if v == "nil" {
+        Value::Nil
+    } else if let Ok(i) = v.parse::<i64>() {
+        Value::Int(i)
+    } else if let Ok(f) = v.parse::<f64>() {
+        Value::Float(f)
+    } else {
+        Value::Str(v.to_string())
+    }
+}
+
+/// Quote string for placeholder if it can not be written as a bare token
+fn placeholder_str(s: &str) -> String {
+    if s.is_empty()
+        || s.chars()
+            .any(|c| c.is_whitespace() || matches!(c, '[' | ']' | '"' | '=' | '\\'))
+    {
+        let mut result = String::from('"');
+        for c in s.chars() {
+            if c == '"' || c == '\\' {
+                result.push('\\');
+            }
+            result.push(c);
+        }
+        result.push('"');
+        result
+    } else {
+        s.to_string()
+    }
+}
+
+fn placeholder_value(v: &Value) -> String {
+    match v {
+        // Strings like `20` or `true` are quoted to keep their type
+        Value::Str(s) if !matches!(parse_attr_value(s), Value::Str(_)) => format!("\"{}\"", s),
+        Value::Str(s) => placeholder_str(s),
+        Value::Float(f) => format!("{:?}", f),
+        v => value_to_string(v),
+    }
+}
+
+/// Read a bare or quoted token of placeholder.
+/// Returns the token, whether it is quoted and the rest of text.
+fn placeholder_token(s: &str) -> Option<(String, bool, &str)> {
+    match s.strip_prefix('"') {
+        Some(r) => {
+            let mut token = String::new();
+            let mut chars = r.char_indices();
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '"' => return Some((token, true, &r[i + 1..])),
+                    '\\' => match chars.next() {
+                        Some((_, c)) if c == '"' || c == '\\' => token.push(c),
+                        _ => return None,
+                    },
+                    c => token.push(c),
+                }
+            }
+            None
+        }
+        None => {
+            let end = s.find([' ', '[', ']', '"', '=', '\\']).unwrap_or(s.len());
+            if end == 0 {
+                return None;
+            }
+            Some((s[..end].to_string(), false, &s[end..]))
+        }
+    }
+}
+
+fn placeholder_token_value(token: String, quoted: bool) -> Value {
+    if quoted {
+        Value::Str(token)
+    } else {
+        parse_attr_value(&token)
+    }
+}
+
+fn value_to_string(v: &Value) -> String {
+    match v {
+        Value::Str(s) => s.clone(),
+        Value::Float(f) => format!("{}", f),
+        Value::Int(i) => format!("{}", i),
+        Value::Nil => "nil".to_string(),
+        v => v.as_bool().map(|b| b.to_string()).unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    /// Members of the dialogue `{...}` in Lua syntax
+    fn dialogue(src: &str) -> Vec<Value> {
+        let src = format!("astver = 2.0\nast = {{{{{}}}}}\n", src);
+        let ast = Parser::new(&src).parse().unwrap().ast;
+        match ast.get_member(0) {
+            Some(Value::Array(arr)) => arr.clone(),
+            v => panic!("not a dialogue: {:?}", v),
+        }
+    }
+
+    fn assert_roundtrip(src: &str) {
+        let values = dialogue(src);
+        let segments = TextSegment::parse(&values).unwrap();
+        assert_eq!(TextSegment::to_values(&segments), values, "{}", src);
+    }
+
+    #[test]
+    fn to_values_gives_original() {
+        assert_roundtrip(
+            r#"name = {"主人公"}, "「", {"ruby", text="かん"}, "漢", {"/ruby"}, "」", {"rt2"}"#,
+        );
+        assert_roundtrip(r#"{"txruby", text="じ"}, "字", {"txruby"}, "あ", {"ret2"}"#);
+        assert_roundtrip(r#""a", {"exfont", size=20}, "大", {"exfont"}, "b""#);
+        assert_roundtrip(r#"{"ruby", text="る"}, "開", {"exfont", size=10}, "小""#);
+        assert_roundtrip(r#"{"ruby", text="る"}, "a", vo = {{"vo", file="v01"}}, {"/ruby"}"#);
+        assert_roundtrip(r#""a", {"wait", time=10}, {"unknown"}, "b", {"exfont"}"#);
+    }
+
+    #[test]
+    fn html_keeps_empty_ruby() {
+        let policy = TagPolicy::default();
+        for src in [
+            r#"{"ruby", text=""}, "漢", {"/ruby"}"#,
+            r#"{"ruby"}, "漢", {"/ruby"}"#,
+        ] {
+            let values = dialogue(src);
+            let segments = TextSegment::parse(&values).unwrap();
+            let html = TextSegment::to_html(&segments, &policy);
+            assert_eq!(html, "<ruby>漢<rt></rt></ruby>");
+            let back = TextSegment::from_html(&html, &segments).unwrap();
+            assert_eq!(TextSegment::to_values(&back), values, "{}", src);
+        }
+    }
+}
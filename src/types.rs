@@ -1,15 +1,80 @@
-use std::collections::{BTreeMap, HashMap};
+use crate::text::{Tag, TagPolicy, TextSegment};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// How the key of [Value::KeyVal] is written
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyKind {
+    /// Identifier, like `key = 1`
+    Name,
+    /// String in brackets, like `["some key"] = 1`
+    Str,
+    /// Integer in brackets, like `[1] = 1`. The key is the decimal integer.
+    Int,
+}
+
+const LUA_KEYWORDS: &[&str] = &[
+    "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if", "in",
+    "local", "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+];
+
+impl KeyKind {
+    /// [KeyKind::Name] if the key can be written as identifier, otherwise [KeyKind::Str].
+    /// Non-ASCII characters are allowed in identifiers like LuaJIT does.
+    pub fn for_key(key: &str) -> Self {
+        let mut chars = key.chars();
+        let first = chars
+            .next()
+            .is_some_and(|c| c == '_' || c.is_ascii_alphabetic() || !c.is_ascii());
+        if first
+            && chars.all(|c| c == '_' || c.is_ascii_alphanumeric() || !c.is_ascii())
+            && !LUA_KEYWORDS.contains(&key)
+        {
+            KeyKind::Name
+        } else {
+            KeyKind::Str
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 pub enum Value {
     Float(f64),
     Int(i64),
     Str(String),
-    KeyVal((String, Box<Value>)),
+    KeyVal((String, Box<Value>, KeyKind)),
     Array(Vec<Value>),
+    Bool(bool),
+    Nil,
+}
+
+/// Values are equal if they have the same structure. Floats are compared by
+/// bits so `NaN` equals `NaN`, the spelling of numbers and comments are ignored.
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Float(a), Value::Float(b)) => {
+                a.to_bits() == b.to_bits() || (a.is_nan() && b.is_nan())
+            }
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Str(a), Value::Str(b)) => a == b,
+            (Value::KeyVal(a), Value::KeyVal(b)) => a == b,
+            (Value::Array(a), Value::Array(b)) => a == b,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Nil, Value::Nil) => true,
+            _ => false,
+        }
+    }
 }
 
 impl Value {
+    /// Create a keyval, the key kind is chosen by [KeyKind::for_key]
+    pub fn key_val<S: Into<String>>(key: S, value: Value) -> Self {
+        let key = key.into();
+        let kind = KeyKind::for_key(&key);
+        Value::KeyVal((key, Box::new(value), kind))
+    }
+
     pub fn as_str(&self) -> Option<&str> {
         match self {
             Value::Str(s) => Some(s),
@@ -24,6 +89,17 @@ impl Value {
         }
     }
 
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn is_nil(&self) -> bool {
+        matches!(self, Value::Nil)
+    }
+
     pub fn is_array(&self) -> bool {
         matches!(self, Value::Array(_))
     }
@@ -52,10 +128,11 @@ impl Value {
         }
     }
 
+    /// Find the value of `key` member, a `nil` value means the key is not set like in Lua
     pub fn find_keyval(&self, key: &str) -> Option<&Value> {
         match self {
-            Value::KeyVal((k, v)) => {
-                if k == key {
+            Value::KeyVal((k, v, _)) => {
+                if k == key && !v.is_nil() {
                     Some(v)
                 } else {
                     None
@@ -64,11 +141,7 @@ impl Value {
             Value::Array(arr) => {
                 for v in arr {
                     match v {
-                        Value::KeyVal((k, v)) => {
-                            if k == key {
-                                return Some(v);
-                            }
-                        }
+                        Value::KeyVal((k, v, _)) if k == key && !v.is_nil() => return Some(v),
                         _ => {}
                     }
                 }
@@ -91,6 +164,185 @@ impl Value {
             _ => None,
         }
     }
+
+    pub fn find_array_attrs_mut(&mut self, key: &str) -> Vec<&mut Value> {
+        match self {
+            Value::Array(arr) => arr
+                .iter_mut()
+                .filter(|v| {
+                    v.get_member(0)
+                        .and_then(|v| v.as_str())
+                        .is_some_and(|s| s == key)
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Mutable version of [Value::find_keyval], a `nil` value means the key is not set
+    pub fn find_keyval_mut(&mut self, key: &str) -> Option<&mut Value> {
+        self.keyval_entry_mut(key).filter(|v| !v.is_nil())
+    }
+
+    /// Find the value of `key` member even if it is `nil`, used to overwrite it
+    pub fn keyval_entry_mut(&mut self, key: &str) -> Option<&mut Value> {
+        match self {
+            Value::KeyVal((k, v, _)) if k == key => Some(v),
+            Value::Array(arr) => arr.iter_mut().find_map(|v| match v {
+                Value::KeyVal((k, v, _)) if k == key => Some(v.as_mut()),
+                _ => None,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Set the value of `key` member. If it not exists, a new member is appended.
+    pub fn set_keyval(&mut self, key: &str, value: Value) {
+        match self.keyval_entry_mut(key) {
+            Some(v) => *v = value,
+            None => {
+                if let Value::Array(arr) = self {
+                    arr.push(Value::key_val(key, value));
+                }
+            }
+        }
+    }
+
+    /// Copy the value of `key` member to `new_key` member.
+    /// If `new_key` not exists, it is inserted after `key` member.
+    /// Returns false if `key` not exists.
+    pub fn clone_keyval(&mut self, key: &str, new_key: &str) -> bool {
+        let value = match self.find_keyval(key) {
+            Some(v) => v.clone(),
+            None => return false,
+        };
+        if let Some(v) = self.keyval_entry_mut(new_key) {
+            *v = value;
+            return true;
+        }
+        match self {
+            Value::Array(arr) => {
+                let pos = arr
+                    .iter()
+                    .position(|v| matches!(v, Value::KeyVal((k, _, _)) if k == key))
+                    .map_or(arr.len(), |p| p + 1);
+                arr.insert(pos, Value::key_val(new_key, value));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Index of `key` member in array, the same one as [Value::find_keyval]
+    pub fn keyval_index(&self, key: &str) -> Option<usize> {
+        match self {
+            Value::Array(arr) => arr
+                .iter()
+                .position(|v| matches!(v, Value::KeyVal((k, v, _)) if k == key && !v.is_nil())),
+            _ => None,
+        }
+    }
+
+    pub fn get_member_mut(&mut self, index: usize) -> Option<&mut Value> {
+        match self {
+            Value::Array(arr) => arr.get_mut(index),
+            _ => None,
+        }
+    }
+}
+
+/// Comments kept by parser, see [crate::parser::Parser::set_keep_comments]
+#[derive(Clone, Debug, Default)]
+pub struct Comments {
+    /// Comments before a value by its position. A position is the indices of
+    /// the value in arrays from the root `ast` table, the index after the last
+    /// member is used for comments before the closing `}` of an array.
+    /// Comments before the root `ast` table use the empty position.
+    pub values: BTreeMap<Vec<usize>, Vec<String>>,
+    /// Comments after the root `ast` table
+    pub trailing: Vec<String>,
+    /// Count of comments which are not kept by parser
+    pub dropped: usize,
+}
+
+impl Comments {
+    pub fn get(&self, pos: &[usize]) -> Option<&Vec<String>> {
+        self.values.get(pos)
+    }
+
+    /// Whether there are comments inside the value at the position
+    pub fn has_inner(&self, pos: &[usize]) -> bool {
+        self.values
+            .range(pos.to_vec()..)
+            .take_while(|(k, _)| k.starts_with(pos))
+            .any(|(k, _)| k.len() > pos.len())
+    }
+}
+
+/// Move values by position (see [Comments::values]) after blocks are reordered,
+/// `order[i]` is the old index of the block at index `i`
+fn reorder_blocks<T>(values: &mut BTreeMap<Vec<usize>, T>, order: &[usize]) {
+    let mut new_index = vec![0; order.len()];
+    for (i, old) in order.iter().enumerate() {
+        new_index[*old] = i;
+    }
+    for (mut pos, v) in std::mem::take(values) {
+        if let Some(i) = pos.first_mut()
+            && *i < new_index.len()
+        {
+            *i = new_index[*i];
+        }
+        values.insert(pos, v);
+    }
+}
+
+/// Line ending used in a file
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    CrLf,
+}
+
+impl LineEnding {
+    /// Detect by the first line break of text, [LineEnding::Lf] if there is no line break
+    pub fn detect(text: &[u8]) -> Self {
+        match text.iter().position(|c| *c == b'\n') {
+            Some(i) if i > 0 && text[i - 1] == b'\r' => LineEnding::CrLf,
+            _ => LineEnding::Lf,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &'static [u8] {
+        match self {
+            LineEnding::Lf => b"\n",
+            LineEnding::CrLf => b"\r\n",
+        }
+    }
+}
+
+/// Where a value is in source, see [crate::parser::Parser::set_keep_spans]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Span {
+    /// Byte offset of the first character
+    pub start: usize,
+    /// Byte offset after the last character
+    pub end: usize,
+    /// Line of the first character, starts from 1
+    pub line: usize,
+    /// Column of the first character in characters, starts from 1
+    pub column: usize,
+}
+
+impl std::fmt::Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {} column {}", self.line, self.column)
+    }
+}
+
+/// ` at line X column Y` if the span is known, used in messages
+pub fn span_suffix(span: Option<Span>) -> String {
+    span.map_or(String::new(), |s| format!(" at {}", s))
 }
 
 #[derive(Debug)]
@@ -98,6 +350,35 @@ pub struct AstFile {
     pub astver: f64,
     pub astname: Option<String>,
     pub ast: Value,
+    /// Comments in the file, empty if comments are not kept by parser.
+    /// Comments are attached by position, so they may move to other values
+    /// if members are inserted or removed.
+    pub comments: Comments,
+    /// Original spelling of numbers by position (see [Comments::values]) if it
+    /// is different from the dumper's, like `1e3` or `0x1F`. The empty position is `astver`.
+    pub numbers: BTreeMap<Vec<usize>, String>,
+    /// Source spans of values by position (see [Comments::values]), empty if
+    /// spans are not kept by parser. A keyval and its value share the span of
+    /// the whole `key = value`. The empty position is the root `ast` table.
+    pub spans: BTreeMap<Vec<usize>, Span>,
+    /// Encoding of the file, the parser always gets UTF-8 text
+    pub encoding: &'static encoding_rs::Encoding,
+    /// Line ending of the file, kept by dumper unless normalized
+    pub line_ending: LineEnding,
+    /// Whether the file starts with BOM
+    pub bom: bool,
+    /// Whether the file ends with a line break
+    pub final_newline: bool,
+}
+
+/// Files are equal if they have the same header and values,
+/// formatting like comments, line endings and encoding is ignored.
+impl PartialEq for AstFile {
+    fn eq(&self, other: &Self) -> bool {
+        Value::Float(self.astver) == Value::Float(other.astver)
+            && self.astname == other.astname
+            && self.ast == other.ast
+    }
 }
 
 impl AstFile {
@@ -107,7 +388,7 @@ impl AstFile {
             Value::Array(arr) => {
                 for v in arr {
                     match v {
-                        Value::KeyVal((k, v)) => {
+                        Value::KeyVal((k, v, _)) => {
                             if let Some(ori) = blocks.get(k) {
                                 let ori_line = ori.find_keyval("line").map_or(None, |v| v.as_int());
                                 let line = v.find_keyval("line").map_or(None, |v| v.as_int());
@@ -131,7 +412,55 @@ impl AstFile {
         blocks
     }
 
-    pub fn get_messages(&self) -> anyhow::Result<Messages> {
+    /// Index of the block with given name in the root `ast` table.
+    /// If multiple blocks have the same name, the same one as [AstFile::get_blocks] is returned.
+    pub fn block_index(&self, name: &str) -> Option<usize> {
+        let arr = match &self.ast {
+            Value::Array(arr) => arr,
+            _ => return None,
+        };
+        let mut found: Option<(usize, Option<i64>)> = None;
+        for (i, v) in arr.iter().enumerate() {
+            match v {
+                Value::KeyVal((k, v, _)) if k == name => {
+                    let line = v.find_keyval("line").and_then(|v| v.as_int());
+                    found = match found {
+                        Some((_, Some(ori_line))) => match line {
+                            Some(line) if line > ori_line => Some((i, Some(line))),
+                            _ => found,
+                        },
+                        Some((_, None)) if line.is_none() => found,
+                        _ => Some((i, line)),
+                    };
+                }
+                _ => {}
+            }
+        }
+        found.map(|(i, _)| i)
+    }
+
+    /// Get a mutable reference to the block with given name.
+    /// If multiple blocks have the same name, the same one as [AstFile::get_blocks] is returned.
+    pub fn get_block_mut(&mut self, name: &str) -> Option<&mut Value> {
+        let i = self.block_index(name)?;
+        match &mut self.ast {
+            Value::Array(arr) => arr[i].find_keyval_mut(name),
+            _ => None,
+        }
+    }
+
+    /// Source span of the value at the position, see [AstFile::spans]
+    pub fn span(&self, pos: &[usize]) -> Option<Span> {
+        self.spans.get(pos).copied()
+    }
+
+    /// Source span of the block with given name
+    pub fn block_span(&self, name: &str) -> Option<Span> {
+        self.block_index(name).and_then(|i| self.span(&[i]))
+    }
+
+    /// Extract messages from blocks, see [ExtractOptions].
+    pub fn get_messages(&self, opts: &ExtractOptions) -> anyhow::Result<Messages> {
         let mut result = Messages::default();
         let blocks = self.get_blocks();
         let label = blocks
@@ -142,7 +471,7 @@ impl AstFile {
             Value::Array(arr) => {
                 for v in arr {
                     match v {
-                        Value::KeyVal((k, v)) => {
+                        Value::KeyVal((k, v, _)) => {
                             let block = v
                                 .find_keyval("block")
                                 .map_or(None, |v| v.as_str())
@@ -159,24 +488,34 @@ impl AstFile {
             }
             _ => {}
         }
-        let mut label = label
-            .find_keyval("top")
-            .map_or(None, |v| v.find_keyval("block"))
-            .map_or(None, |v| v.as_str())
-            .ok_or(anyhow::anyhow!("Can not get top block from label"))?;
-        loop {
+        let order = match &opts.order {
+            Some(order) => order.clone(),
+            None => {
+                let top = label
+                    .find_keyval("top")
+                    .and_then(|v| v.find_keyval("block"))
+                    .and_then(|v| v.as_str())
+                    .ok_or(anyhow::anyhow!("Can not get top block from label"))?;
+                self.linknext_chain(&blocks, top)
+            }
+        };
+        let policy = &opts.policy;
+        for label in order.iter() {
+            let label = label.as_str();
             let block = match blocks.get(label) {
                 Some(b) => b,
-                None => break,
+                None => continue,
             };
+            let block_index = self.block_index(label);
             if result.savetitle.is_none() {
                 if let Some(v) = block.find_array_attrs("savetitle").first() {
+                    result.savetitle_block = Some(label.to_string());
                     let mut title = BTreeMap::new();
                     match v {
                         Value::Array(arr) => {
                             for v in arr {
                                 match v {
-                                    Value::KeyVal((k, v)) => {
+                                    Value::KeyVal((k, v, _)) => {
                                         if let Some(v) = v.as_str() {
                                             title.insert(k.to_string(), v.to_string());
                                         }
@@ -216,21 +555,25 @@ impl AstFile {
                 if selects.is_empty() {
                     let text = match block.find_keyval("text") {
                         Some(v) => v,
-                        None => {
-                            label = match block.find_keyval("linknext").map_or(None, |v| v.as_str())
-                            {
-                                Some(v) => v,
-                                None => break,
-                            };
-                            continue;
-                        }
+                        None => continue,
                     };
+                    let text_pos = block_index.zip(block.keyval_index("text"));
                     match text {
                         Value::Array(v) => {
                             let mut tmp = BTreeMap::new();
-                            for t in v {
+                            let mut unknown_tags: Vec<String> = Vec::new();
+                            let voices: Vec<Voice> = text
+                                .find_keyval("vo")
+                                .map(|v| match v {
+                                    Value::Array(arr) => {
+                                        arr.iter().filter_map(Voice::from_value).collect()
+                                    }
+                                    _ => Vec::new(),
+                                })
+                                .unwrap_or_default();
+                            for (lang_index, t) in v.iter().enumerate() {
                                 match t {
-                                    Value::KeyVal((k, v)) => {
+                                    Value::KeyVal((k, v, _)) => {
                                         if k == "vo" {
                                             continue;
                                         }
@@ -242,156 +585,58 @@ impl AstFile {
                                         };
                                         match v.as_ref() {
                                             Value::Array(v) => {
-                                                for v in v {
+                                                for (index, v) in v.iter().enumerate() {
+                                                    let span = text_pos.and_then(|(b, t)| {
+                                                        self.span(&[b, t, lang_index, index])
+                                                    });
                                                     let name = v
                                                         .find_keyval("name")
                                                         .map_or(None, |v| v.last())
                                                         .map_or(None, |v| v.as_str())
                                                         .map(|v| v.to_string());
-                                                    let mut text = String::new();
-                                                    let mut ruby_rt = None;
-                                                    let mut in_exfont = false;
-                                                    match v {
+                                                    let segments = match v {
                                                         Value::Array(v) => {
-                                                            for v in v {
-                                                                match v {
-                                                                    Value::Str(s) => {
-                                                                        text.push_str(s)
-                                                                    }
-                                                                    Value::Array(s) => {
-                                                                        let ok = if s.len() >= 1 {
-                                                                            if let Value::Str(s) =
-                                                                                &s[0]
-                                                                            {
-                                                                                if s == "rt2"
-                                                                                    || s == "ret2"
-                                                                                {
-                                                                                    text.push_str(
-                                                                                        "\n",
-                                                                                    );
-                                                                                    true
-                                                                                } else if s
-                                                                                    == "txruby"
-                                                                                {
-                                                                                    if let Some(
-                                                                                        rt,
-                                                                                    ) = &ruby_rt
-                                                                                    {
-                                                                                        text.push_str(&format!("<rt>{}</rt></ruby>", rt));
-                                                                                        ruby_rt =
-                                                                                            None;
-                                                                                    } else {
-                                                                                        let rt = v.find_keyval("text").map_or(None, |v| v.as_str()).unwrap_or("");
-                                                                                        if !rt.is_empty() {
-                                                                                            text.push_str("<ruby>");
-                                                                                            ruby_rt = Some(rt);
-                                                                                        }
-                                                                                    }
-                                                                                    true
-                                                                                } else if s
-                                                                                    == "ruby"
-                                                                                {
-                                                                                    let rt = v.find_keyval("text").map_or(None, |v| v.as_str()).unwrap_or("");
-                                                                                    if !rt
-                                                                                        .is_empty()
-                                                                                    {
-                                                                                        text.push_str("<ruby>");
-                                                                                        ruby_rt =
-                                                                                            Some(
-                                                                                                rt,
-                                                                                            );
-                                                                                    }
-                                                                                    true
-                                                                                } else if s
-                                                                                    == "/ruby"
-                                                                                {
-                                                                                    if let Some(
-                                                                                        rt,
-                                                                                    ) = &ruby_rt
-                                                                                    {
-                                                                                        text.push_str(&format!("<rt>{}</rt></ruby>", rt));
-                                                                                        ruby_rt =
-                                                                                            None;
-                                                                                    } else {
-                                                                                        text.push_str("</ruby>");
-                                                                                    }
-                                                                                    true
-                                                                                } else if s
-                                                                                    == "exfont"
-                                                                                {
-                                                                                    text.push('<');
-                                                                                    in_exfont =
-                                                                                        !in_exfont;
-                                                                                    if !in_exfont {
-                                                                                        text.push(
-                                                                                            '/',
-                                                                                        );
-                                                                                    }
-                                                                                    text.push_str(
-                                                                                        s,
-                                                                                    );
-                                                                                    match v {
-                                                                                        Value::Array(arr) => {
-                                                                                            for v in arr {
-                                                                                                match v {
-                                                                                                    Value::KeyVal((k, v)) => {
-                                                                                                        let v = match v.as_ref() {
-                                                                                                            Value::Str(s) => s.clone(),
-                                                                                                            Value::Float(s) => {
-                                                                                                                let s = format!("{}", s);
-                                                                                                                s
-                                                                                                            }
-                                                                                                            Value::Int(s) => {
-                                                                                                                let s = format!("{}", s);
-                                                                                                                s
-                                                                                                            }
-                                                                                                            _ => String::new(),
-                                                                                                        };
-                                                                                                        text.push_str(&format!(" {}=\"{}\"", k, v));
-                                                                                                    }
-                                                                                                    _ => {}
-                                                                                                }
-                                                                                            }
-                                                                                        }
-                                                                                        _ => {}
-                                                                                    }
-                                                                                    text.push('>');
-                                                                                    true
-                                                                                } else {
-                                                                                    false
-                                                                                }
-                                                                            } else {
-                                                                                false
-                                                                            }
-                                                                        } else {
-                                                                            false
-                                                                        };
-                                                                        if !ok {
-                                                                            return Err(
-                                                                                anyhow::anyhow!(
-                                                                                    "Invalid text in dialogue block {}: {:?}",
-                                                                                    label,
-                                                                                    v
-                                                                                ),
-                                                                            );
-                                                                        }
-                                                                    }
-                                                                    Value::KeyVal(_) => {}
-                                                                    _ => {
-                                                                        return Err(
-                                                                            anyhow::anyhow!(
-                                                                                "Invalid text in dialogue block {}: {:?}",
-                                                                                label,
-                                                                                v
-                                                                            ),
-                                                                        );
-                                                                    }
+                                                            TextSegment::parse(v).map_err(|e| {
+                                                                anyhow::anyhow!(
+                                                                    "Invalid text in dialogue block {}{}: {}",
+                                                                    label,
+                                                                    span_suffix(span),
+                                                                    e
+                                                                )
+                                                            })?
+                                                        }
+                                                        _ => Vec::new(),
+                                                    };
+                                                    match policy.check(&segments) {
+                                                        Ok(unknown) => {
+                                                            for tag in unknown {
+                                                                if !unknown_tags
+                                                                    .iter()
+                                                                    .any(|t| t == tag)
+                                                                {
+                                                                    unknown_tags
+                                                                        .push(tag.to_string());
                                                                 }
                                                             }
                                                         }
-                                                        _ => {}
+                                                        Err(tag) => {
+                                                            return Err(anyhow::anyhow!(
+                                                                "Invalid text in dialogue block {}{}: {:?}",
+                                                                label,
+                                                                span_suffix(span),
+                                                                tag.to_value()
+                                                            ));
+                                                        }
                                                     }
-                                                    vec.push(Dialogue { text, name });
+                                                    let text =
+                                                        TextSegment::to_html(&segments, policy);
+                                                    vec.push(Dialogue {
+                                                        text,
+                                                        name,
+                                                        segments,
+                                                        voices: voices.clone(),
+                                                        span,
+                                                    });
                                                 }
                                             }
                                             _ => {}
@@ -400,7 +645,15 @@ impl AstFile {
                                     _ => {}
                                 }
                             }
+                            if !unknown_tags.is_empty() {
+                                result.warnings.push(format!(
+                                    "Unknown tags in dialogue block {}: {}",
+                                    label,
+                                    unknown_tags.join(", ")
+                                ));
+                            }
                             result.messages.push(Message::Dialogue(Dialogues {
+                                block: label.to_string(),
                                 labels: la,
                                 dialogues: tmp,
                             }));
@@ -428,16 +681,18 @@ impl AstFile {
                             used.insert(text, BTreeMap::new());
                         }
                         let count_map = used.get_mut(text).unwrap();
+                        let text_key = text;
                         let text_block = block.find_keyval(text).ok_or(anyhow::anyhow!(
                             "Can not get text block {} from select block {}",
                             text,
                             label
                         ))?;
+                        let text_pos = block_index.zip(block.keyval_index(text));
                         match text_block {
                             Value::Array(v) => {
-                                for v in v {
+                                for (lang_index, v) in v.iter().enumerate() {
                                     match v {
-                                        Value::KeyVal((k, v)) => {
+                                        Value::KeyVal((k, v, _)) => {
                                             let vec = if tmp.contains_key(k) {
                                                 tmp.get_mut(k).unwrap()
                                             } else {
@@ -457,12 +712,17 @@ impl AstFile {
                                                     "Can not get text from select block {}",
                                                     label
                                                 ))?;
-                                            *count += 1;
                                             vec.push(Select {
                                                 text: text.to_string(),
                                                 file: file.clone(),
                                                 label: slabel.clone(),
+                                                text_key: text_key.to_string(),
+                                                index: *count,
+                                                span: text_pos.and_then(|(b, t)| {
+                                                    self.span(&[b, t, lang_index, *count])
+                                                }),
                                             });
+                                            *count += 1;
                                         }
                                         _ => {}
                                     }
@@ -472,17 +732,32 @@ impl AstFile {
                         }
                     }
                     result.messages.push(Message::Select(Selects {
+                        block: label.to_string(),
                         labels: la,
                         sels: tmp,
                     }));
                 }
             }
-            label = match block.find_keyval("linknext").map_or(None, |v| v.as_str()) {
+        }
+        Ok(result)
+    }
+
+    /// Names of blocks by following `linknext` from `start`
+    fn linknext_chain(&self, blocks: &HashMap<String, Box<Value>>, start: &str) -> Vec<String> {
+        let mut order = Vec::new();
+        let mut visited = HashSet::new();
+        let mut label = start;
+        while let Some(block) = blocks.get(label) {
+            if !visited.insert(label) {
+                break;
+            }
+            order.push(label.to_string());
+            label = match block.find_keyval("linknext").and_then(|v| v.as_str()) {
                 Some(v) => v,
                 None => break,
             };
         }
-        Ok(result)
+        order
     }
 
     pub fn sort_blocks(&mut self) {
@@ -490,39 +765,94 @@ impl AstFile {
             Value::Array(arr) => {
                 let mut maps = BTreeMap::new();
                 let mut others = Vec::new();
-                while let Some(o) = arr.pop() {
+                for (i, o) in std::mem::take(arr).into_iter().enumerate().rev() {
                     match o {
-                        Value::KeyVal((k, v)) => {
+                        Value::KeyVal((k, v, kind)) => {
                             let line = v.find_keyval("line").map_or(None, |v| v.as_int());
                             if let Some(line) = line {
-                                maps.insert(line, Value::KeyVal((k, v)));
+                                maps.insert((line, i), (i, Value::KeyVal((k, v, kind))));
                             } else {
-                                others.push(Value::KeyVal((k, v)));
+                                others.push((i, Value::KeyVal((k, v, kind))));
                             }
                         }
-                        _ => others.push(o),
+                        _ => others.push((i, o)),
                     }
                 }
-                for (_, v) in maps {
+                let mut order = Vec::new();
+                for (i, v) in maps.into_values().chain(others) {
+                    order.push(i);
                     arr.push(v);
                 }
-                for o in others {
-                    arr.push(o);
-                }
+                reorder_blocks(&mut self.comments.values, &order);
+                reorder_blocks(&mut self.numbers, &order);
+                reorder_blocks(&mut self.spans, &order);
             }
             _ => {}
         }
     }
 }
 
+/// Options for [AstFile::get_messages]
+#[derive(Clone, Debug, Default)]
+pub struct ExtractOptions {
+    /// Tags in dialogues which are not handled by [TextSegment] are rendered by the policy
+    pub policy: TagPolicy,
+    /// Names of blocks to extract in order.
+    /// If not specified, follow `linknext` from the top label.
+    pub order: Option<Vec<String>>,
+}
+
 #[derive(Debug)]
 pub struct Dialogue {
+    /// Text with `\n` for line breaks, `<ruby>` and `<exfont>` tags
     pub text: String,
     pub name: Option<String>,
+    /// Structured text which can be converted back to values
+    pub segments: Vec<TextSegment>,
+    /// Voices of the text block
+    pub voices: Vec<Voice>,
+    /// Where the dialogue is in source
+    pub span: Option<Span>,
+}
+
+/// A voice entry in `vo` of text block, like `{"vo", file="v001", ch="hero"}`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Voice {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    /// Character channel
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ch: Option<String>,
+    /// Other attributes
+    #[serde(flatten)]
+    pub attrs: BTreeMap<String, String>,
+}
+
+impl Voice {
+    pub fn from_value(v: &Value) -> Option<Self> {
+        let tag = Tag::from_value(v)?;
+        let mut voice = Voice {
+            file: None,
+            ch: None,
+            attrs: BTreeMap::new(),
+        };
+        for (k, v) in tag.keyvals() {
+            match k {
+                "file" => voice.file = Some(v),
+                "ch" => voice.ch = Some(v),
+                _ => {
+                    voice.attrs.insert(k.to_string(), v);
+                }
+            }
+        }
+        Some(voice)
+    }
 }
 
 #[derive(Debug)]
 pub struct Dialogues {
+    /// Name of the block which contains the dialogues
+    pub block: String,
     pub labels: Option<Vec<String>>,
     pub dialogues: BTreeMap<String, Vec<Dialogue>>,
 }
@@ -544,10 +874,18 @@ pub struct Select {
     pub text: String,
     pub file: Option<String>,
     pub label: Option<String>,
+    /// Key of the block member which contains the texts of all languages
+    pub text_key: String,
+    /// Index of the text in the language array
+    pub index: usize,
+    /// Where the text is in source
+    pub span: Option<Span>,
 }
 
 #[derive(Debug)]
 pub struct Selects {
+    /// Name of the block which contains the selects
+    pub block: String,
     pub labels: Option<Vec<String>>,
     pub sels: BTreeMap<String, Vec<Select>>,
 }
@@ -562,5 +900,9 @@ pub enum Message {
 #[derive(Debug, Default)]
 pub struct Messages {
     pub savetitle: Option<BTreeMap<String, String>>,
+    /// Name of the block which contains the savetitle
+    pub savetitle_block: Option<String>,
     pub messages: Vec<Message>,
+    /// Warnings like unknown tags found in dialogues
+    pub warnings: Vec<String>,
 }
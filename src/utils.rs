@@ -102,3 +102,41 @@ pub fn write_file<F: AsRef<Path> + ?Sized>(f: &F) -> io::Result<Box<dyn Write>>
         Box::new(fs::File::create(f)?)
     })
 }
+
+/// Replace file with content safely. The content is written to a temporary file in
+/// the same directory, synced and renamed over the file, so the file is never left
+/// half written. If `backup` is given, the previous version is kept in the file with
+/// this suffix. `-` writes to stdout.
+pub fn write_file_atomic<F: AsRef<Path> + ?Sized>(
+    f: &F,
+    content: &[u8],
+    backup: Option<&str>,
+) -> io::Result<()> {
+    let path = f.as_ref();
+    if path == Path::new("-") {
+        return io::stdout().write_all(content);
+    }
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+    tmp.write_all(content)?;
+    if let Ok(meta) = fs::metadata(path) {
+        tmp.as_file().set_permissions(meta.permissions())?;
+    }
+    tmp.as_file().sync_all()?;
+    if let Some(suffix) = backup
+        && path.exists()
+    {
+        let mut backup = path.as_os_str().to_owned();
+        backup.push(suffix);
+        fs::copy(path, backup)?;
+    }
+    tmp.persist(path).map_err(|e| e.error)?;
+    // Make the rename durable, not supported on every platform
+    if let Ok(dir) = fs::File::open(dir) {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
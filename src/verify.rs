@@ -0,0 +1,163 @@
+use crate::types::{AstFile, KeyKind, Value};
+
+/// A difference between two files, `path` is like `ast.block_00000[2].text.ja`
+#[derive(Clone, Debug)]
+pub struct Divergence {
+    pub path: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// Path of a keyval member, like `.key`, `["some key"]` or `[1]`
+pub fn key_path(key: &str, kind: KeyKind) -> String {
+    match kind {
+        KeyKind::Name => format!(".{}", key),
+        KeyKind::Str => format!("[{:?}]", key),
+        KeyKind::Int => format!("[{}]", key),
+    }
+}
+
+/// Paths of members of a table relative to it. Members without key are
+/// numbered from 0, so `{"a", k = 1, "b"}` gives `[0]`, `.k` and `[1]`.
+pub fn member_paths(arr: &[Value]) -> Vec<String> {
+    let mut index = 0;
+    arr.iter()
+        .map(|v| match v {
+            Value::KeyVal((k, _, kind)) => key_path(k, *kind),
+            _ => {
+                index += 1;
+                format!("[{}]", index - 1)
+            }
+        })
+        .collect()
+}
+
+/// Path of the value at a position (see [crate::types::Comments::values]) in `ast`.
+/// The position after the last member of a table gives `(end)`.
+pub fn position_path(ast: &Value, pos: &[usize]) -> String {
+    let mut path = "ast".to_string();
+    let mut value = ast;
+    for i in pos {
+        let arr = match value {
+            Value::KeyVal((_, v, _)) => match v.as_ref() {
+                Value::Array(arr) => arr,
+                _ => break,
+            },
+            Value::Array(arr) => arr,
+            _ => break,
+        };
+        match member_paths(arr).get(*i) {
+            Some(p) => path.push_str(p),
+            None => {
+                path.push_str("(end)");
+                break;
+            }
+        }
+        value = &arr[*i];
+    }
+    path
+}
+
+fn type_name(v: &Value) -> &'static str {
+    match v {
+        Value::Float(_) => "float",
+        Value::Int(_) => "integer",
+        Value::Str(_) => "string",
+        Value::KeyVal(_) => "keyval",
+        Value::Array(_) => "table",
+        Value::Bool(_) => "boolean",
+        Value::Nil => "nil",
+    }
+}
+
+/// Compare values in order of members, differences are pushed to `result`
+pub fn compare_value(a: &Value, b: &Value, path: &str, result: &mut Vec<Divergence>) {
+    let mut push = |message: String| {
+        result.push(Divergence {
+            path: path.to_string(),
+            message,
+        })
+    };
+    match (a, b) {
+        (Value::Float(x), Value::Float(y)) => {
+            if x.to_bits() != y.to_bits() && !(x.is_nan() && y.is_nan()) {
+                push(format!("{} != {}", x, y));
+            }
+        }
+        (Value::Int(x), Value::Int(y)) if x != y => push(format!("{} != {}", x, y)),
+        (Value::Str(x), Value::Str(y)) if x != y => push(format!("{:?} != {:?}", x, y)),
+        (Value::Bool(x), Value::Bool(y)) if x != y => push(format!("{} != {}", x, y)),
+        (Value::KeyVal((k, v, kind)), Value::KeyVal((k2, v2, kind2))) => {
+            if k != k2 || kind != kind2 {
+                push(format!(
+                    "key {} != {}",
+                    key_path(k, *kind),
+                    key_path(k2, *kind2)
+                ));
+            } else {
+                compare_value(v, v2, path, result);
+            }
+        }
+        (Value::Array(x), Value::Array(y)) => {
+            if x.len() != y.len() {
+                push(format!("{} members != {} members", x.len(), y.len()));
+            }
+            for ((p, x), y) in member_paths(x).iter().zip(x).zip(y) {
+                compare_value(x, y, &format!("{}{}", path, p), result);
+            }
+        }
+        (Value::Int(_), Value::Int(_))
+        | (Value::Str(_), Value::Str(_))
+        | (Value::Bool(_), Value::Bool(_))
+        | (Value::Nil, Value::Nil) => {}
+        (a, b) => push(format!("{} != {}", type_name(a), type_name(b))),
+    }
+}
+
+/// Compare header, values and comments of files
+pub fn compare(a: &AstFile, b: &AstFile) -> Vec<Divergence> {
+    let mut result = Vec::new();
+    compare_value(
+        &Value::Float(a.astver),
+        &Value::Float(b.astver),
+        "astver",
+        &mut result,
+    );
+    if a.astname != b.astname {
+        result.push(Divergence {
+            path: "astname".to_string(),
+            message: format!("{:?} != {:?}", a.astname, b.astname),
+        });
+    }
+    compare_value(&a.ast, &b.ast, "ast", &mut result);
+    let positions: std::collections::BTreeSet<_> = a
+        .comments
+        .values
+        .keys()
+        .chain(b.comments.values.keys())
+        .collect();
+    for pos in positions {
+        let (x, y) = (a.comments.get(pos), b.comments.get(pos));
+        if x != y {
+            result.push(Divergence {
+                path: position_path(&a.ast, pos),
+                message: format!("comments {:?} != {:?}", x, y),
+            });
+        }
+    }
+    if a.comments.trailing != b.comments.trailing {
+        result.push(Divergence {
+            path: "ast".to_string(),
+            message: format!(
+                "trailing comments {:?} != {:?}",
+                a.comments.trailing, b.comments.trailing
+            ),
+        });
+    }
+    result
+}
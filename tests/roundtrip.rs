@@ -0,0 +1,50 @@
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Files of the round-trip corpus in `tests/roundtrip`
+fn corpus() -> Vec<PathBuf> {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/roundtrip");
+    let mut files: Vec<PathBuf> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|e| e == "ast"))
+        .collect();
+    files.sort();
+    assert!(!files.is_empty(), "no .ast files in {}", dir.display());
+    files
+}
+
+/// Run `verify` on every file of corpus with the global options, fails on any divergence
+fn verify(options: &[&str]) {
+    for f in corpus() {
+        let output = Command::new(env!("CARGO_BIN_EXE_artemis_tools"))
+            .args(options)
+            .arg("verify")
+            .arg(&f)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "verify {} with {:?} failed:\n{}{}",
+            f.display(),
+            options,
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+}
+
+#[test]
+fn roundtrip() {
+    verify(&[]);
+}
+
+#[test]
+fn roundtrip_keep_comments() {
+    verify(&["--keep-comments"]);
+}
+
+#[test]
+fn roundtrip_no_indent() {
+    verify(&["--keep-comments", "-n"]);
+}